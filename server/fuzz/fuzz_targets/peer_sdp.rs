@@ -0,0 +1,46 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use once_cell::sync::Lazy;
+use tokio::runtime::Runtime;
+use zoom_server::{
+    config::Config,
+    error,
+    peer::{Mode, Peer, Profile},
+    signal::{self, Capabilities, Role},
+};
+
+static RUNTIME: Lazy<Runtime> = Lazy::new(|| {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+});
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&selector, sdp)) = data.split_first() else {
+        return;
+    };
+    let sdp = String::from_utf8_lossy(sdp).into_owned();
+    RUNTIME.block_on(async {
+        let signal_tx = signal::Sender::new(&Config::default());
+        let profile = Profile {
+            name: "fuzz".into(),
+            client_version: "fuzz".into(),
+            capabilities: Capabilities::default(),
+            authenticated: false,
+        };
+        let (error_tx, _error_rx) = error::channel();
+        let mut peer = Peer::new(1, 1, Mode::Signal, Role::Host, profile, signal_tx, error_tx)
+            .await
+            .unwrap();
+        if selector % 2 == 0 {
+            let _ = peer.recv_offer(1, sdp).await;
+        } else {
+            peer.send_offer().await.unwrap();
+            let re = peer.negotiation.offer_id;
+            let _ = peer.recv_answer(re, sdp).await;
+        }
+        peer.close().await.unwrap();
+    });
+});
@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use zoom_server::signal::{self, Frame};
+
+fuzz_target!(|data: &[u8]| {
+    let text = String::from_utf8_lossy(data);
+    for frame in [Frame::Binary(data), Frame::Text(&text)] {
+        let _ = signal::parse(frame, false);
+        let _ = signal::parse(frame, true);
+    }
+});
@@ -0,0 +1,20 @@
+use std::{env, fs, path::PathBuf};
+
+use zoom_server::schema;
+
+/// Writes the JSON Schemas, TypeScript definitions and constants of the signaling
+/// protocol into the given directory, `../web/src/protocol` by default.
+fn main() {
+    let dir = PathBuf::from(
+        env::args()
+            .nth(1)
+            .unwrap_or_else(|| "../web/src/protocol".into()),
+    );
+    fs::create_dir_all(&dir).unwrap();
+    for (name, schema) in schema::json_schemas() {
+        let json = serde_json::to_string_pretty(&schema).unwrap();
+        fs::write(dir.join(format!("{}.schema.json", name)), json + "\n").unwrap();
+    }
+    fs::write(dir.join("signal.d.ts"), schema::typescript()).unwrap();
+    fs::write(dir.join("constants.ts"), schema::typescript_constants()).unwrap();
+}
@@ -0,0 +1,305 @@
+//! Breakout rooms split a meeting into smaller rooms for a while. Peers move between
+//! the rooms on their existing connections, and return to the main room once the
+//! breakout rooms close.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use rand::seq::SliceRandom;
+use tokio::{
+    sync::Mutex,
+    time::{MissedTickBehavior, interval_at},
+};
+
+use crate::{
+    error::{Error, Result},
+    peer::Mode,
+    room::{Breakouts, Room},
+    signal::{AssignBreakout, OpenBreakouts, Role, ServerMessage},
+    state::State,
+};
+
+/// Opens breakout rooms off the main room, which is locked by the caller, and counts
+/// down to their close.
+pub async fn open(
+    state: &Arc<State>,
+    code: &str,
+    main: &mut Room,
+    open: OpenBreakouts,
+) -> Result<()> {
+    let count = open.names.len();
+    let rooms = (0..count as u32)
+        .zip(&open.names)
+        .map(|(index, name)| state.new_breakout_room(index, name.clone()))
+        .collect();
+    let ends = Instant::now() + Duration::from_secs(open.seconds.into());
+    let breakouts = Breakouts {
+        names: open.names,
+        rooms,
+        ends,
+    };
+    main.broadcast(ServerMessage::Breakouts(breakouts.info()))
+        .await?;
+    main.breakouts = Some(breakouts);
+    println!(
+        "[{}] {} breakout rooms opened for {}s",
+        code, count, open.seconds
+    );
+    let state = Arc::clone(state);
+    let code = code.to_string();
+    tokio::spawn(async move {
+        if let Err(e) = count_down(&state, &code, ends).await {
+            println!("[{}] breakout rooms: {}", code, e);
+        }
+    });
+    Ok(())
+}
+
+/// Sends the meeting the seconds left of the breakout rooms that end at `ends` during
+/// their last `breakout_countdown`, then closes them, unless they were closed before.
+async fn count_down(state: &State, code: &str, ends: Instant) -> Result<()> {
+    let start = ends
+        .checked_sub(state.config.breakout_countdown)
+        .unwrap_or(ends);
+    let mut ticks = interval_at(start.into(), Duration::from_secs(1));
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticks.tick().await;
+        let left = ends.saturating_duration_since(Instant::now());
+        let seconds = left.as_secs_f32().round() as u32;
+        if seconds == 0 {
+            return close(state, code, Some(ends)).await;
+        }
+        let Some(rooms) = meeting_rooms(state, code, ends).await else {
+            return Ok(());
+        };
+        for room in rooms {
+            room.lock()
+                .await
+                .broadcast(ServerMessage::BreakoutCountdown(seconds))
+                .await?;
+        }
+    }
+}
+
+/// The main room followed by its breakout rooms, if the ones that end at `ends` are
+/// still open. The rooms are locked one at a time, like moves expect.
+async fn meeting_rooms(state: &State, code: &str, ends: Instant) -> Option<Vec<Arc<Mutex<Room>>>> {
+    let main = state.find_room(code)?;
+    let main_guard = main.lock().await;
+    let breakouts = main_guard.breakouts.as_ref()?;
+    if breakouts.ends != ends {
+        return None;
+    }
+    let mut rooms = vec![Arc::clone(&main)];
+    rooms.extend(breakouts.rooms.iter().cloned());
+    Some(rooms)
+}
+
+/// Moves a peer other than the host to a breakout room, or back to the main room.
+pub async fn assign(state: &State, code: &str, assign: AssignBreakout) -> Result<()> {
+    let Some(main) = state.find_room(code) else {
+        return Ok(());
+    };
+    let to = match assign.room {
+        None => Arc::clone(&main),
+        Some(index) => main
+            .lock()
+            .await
+            .breakouts
+            .as_ref()
+            .and_then(|breakouts| breakouts.rooms.get(index as usize))
+            .cloned()
+            .ok_or(Error::UnknownBreakout(index))?,
+    };
+    let session = state
+        .room_sessions(code)
+        .into_iter()
+        .find(|session| session.peer_id() == assign.peer && session.mode() == Mode::Signal)
+        .ok_or(Error::UnknownPeer(assign.peer))?;
+    match session.role().await {
+        None => Err(Error::UnknownPeer(assign.peer)),
+        Some(Role::Host) => Err(Error::Forbidden("move the host")),
+        Some(_) => session.move_to(to).await,
+    }
+}
+
+/// Spreads the participants in the main room across the breakout rooms at random, as
+/// evenly as they go. Moderators stay where they are.
+pub async fn shuffle(state: &State, code: &str) -> Result<()> {
+    let Some(main) = state.find_room(code) else {
+        return Ok(());
+    };
+    let rooms = match &main.lock().await.breakouts {
+        Some(breakouts) => breakouts.rooms.clone(),
+        None => return Err(Error::Forbidden("shuffle without breakout rooms")),
+    };
+    let mut participants = Vec::new();
+    for session in state.room_sessions(code) {
+        if session.mode() == Mode::Signal
+            && Arc::ptr_eq(&session.room(), &main)
+            && session.role().await == Some(Role::Participant)
+        {
+            participants.push(session);
+        }
+    }
+    participants.shuffle(&mut rand::rng());
+    for (session, room) in participants.iter().zip(rooms.iter().cycle()) {
+        session.move_to(Arc::clone(room)).await?;
+    }
+    Ok(())
+}
+
+/// Closes the breakout rooms, or only the ones that end at `ends`, and moves everyone
+/// in them back to the main room. A failed move doesn't keep the others in a closed
+/// breakout room; the first error is returned once everyone else is back.
+pub async fn close(state: &State, code: &str, ends: Option<Instant>) -> Result<()> {
+    let Some(main) = state.find_room(code) else {
+        return Ok(());
+    };
+    let mut main_guard = main.lock().await;
+    let Some(breakouts) = main_guard
+        .breakouts
+        .take_if(|breakouts| ends.is_none_or(|ends| breakouts.ends == ends))
+    else {
+        return Ok(());
+    };
+    let mut errors = Vec::new();
+    if let Err(e) = main_guard.broadcast(ServerMessage::BreakoutsClosed).await {
+        errors.push(e);
+    }
+    drop(main_guard);
+    for room in &breakouts.rooms {
+        let closed = room
+            .lock()
+            .await
+            .broadcast(ServerMessage::BreakoutsClosed)
+            .await;
+        if let Err(e) = closed {
+            errors.push(e);
+        }
+    }
+    for session in state.room_sessions(code) {
+        if let Err(e) = session.move_to(Arc::clone(&main)).await {
+            errors.push(e);
+        }
+    }
+    println!("[{}] breakout rooms closed", code);
+    // Everyone may have left from the breakout rooms.
+    let main_guard = main.lock().await;
+    if !main_guard.is_occupied() {
+        state.remove_room(code, &main_guard);
+    }
+    drop(main_guard);
+    let mut errors = errors.into_iter();
+    let Some(first) = errors.next() else {
+        return Ok(());
+    };
+    for e in errors {
+        println!("[{}] breakout rooms: {}", code, e);
+    }
+    Err(first)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::Config,
+        signal::{PeerMessage, Role, SetRole},
+        testing::{envelope, join, reserve},
+    };
+
+    #[tokio::test]
+    async fn assigned_peers_return_when_breakouts_close() {
+        let state = Arc::new(State::new(Config::default()));
+        let code = reserve(&state).await;
+        let host = join(&state, &code, "host").await;
+        let guest = join(&state, &code, "guest").await;
+        let main = host.room();
+        let mut main_guard = main.lock().await;
+        let names = vec!["a".into(), "b".into()];
+        let breakouts = OpenBreakouts { names, seconds: 60 };
+        open(&state, &code, &mut main_guard, breakouts)
+            .await
+            .unwrap();
+        let rooms = main_guard.breakouts.as_ref().unwrap().rooms.clone();
+        drop(main_guard);
+
+        let to_second = AssignBreakout {
+            peer: guest.peer_id(),
+            room: Some(1),
+        };
+        assign(&state, &code, to_second).await.unwrap();
+        assert!(Arc::ptr_eq(&guest.room(), &rooms[1]));
+        assert!(rooms[1].lock().await.has_peer(guest.peer_id()));
+        assert!(!main.lock().await.has_peer(guest.peer_id()));
+        let to_host = AssignBreakout {
+            peer: host.peer_id(),
+            room: Some(0),
+        };
+        assert!(matches!(
+            assign(&state, &code, to_host).await,
+            Err(Error::Forbidden(_))
+        ));
+        let to_missing = AssignBreakout {
+            peer: guest.peer_id(),
+            room: Some(2),
+        };
+        assert!(matches!(
+            assign(&state, &code, to_missing).await,
+            Err(Error::UnknownBreakout(2))
+        ));
+
+        close(&state, &code, None).await.unwrap();
+        assert!(Arc::ptr_eq(&guest.room(), &main));
+        let main_guard = main.lock().await;
+        assert!(main_guard.breakouts.is_none());
+        assert!(main_guard.has_peer(guest.peer_id()));
+        assert!(!rooms[1].lock().await.has_peer(guest.peer_id()));
+    }
+
+    #[tokio::test]
+    async fn main_room_left_empty_for_breakouts_stays_taken() {
+        let state = Arc::new(State::new(Config::default()));
+        let code = reserve(&state).await;
+        let host = join(&state, &code, "host").await;
+        let co_host = join(&state, &code, "co-host").await;
+        let set_role = SetRole {
+            peer: co_host.peer_id(),
+            role: Role::CoHost,
+        };
+        host.handle_message(envelope(PeerMessage::SetRole(set_role)))
+            .await
+            .unwrap();
+        let breakouts = OpenBreakouts {
+            names: vec!["a".into()],
+            seconds: 60,
+        };
+        host.handle_message(envelope(PeerMessage::OpenBreakouts(breakouts)))
+            .await
+            .unwrap();
+        let to_breakout = AssignBreakout {
+            peer: co_host.peer_id(),
+            room: Some(0),
+        };
+        assign(&state, &code, to_breakout).await.unwrap();
+        host.leave().await.unwrap();
+        let main = state.find_room(&code).unwrap();
+        assert!(main.lock().await.peers.is_empty());
+
+        // Joining the empty main room doesn't make anyone its new host.
+        let late = join(&state, &code, "late").await;
+        assert_eq!(late.role().await, Some(Role::Participant));
+        late.leave().await.unwrap();
+        assert!(state.find_room(&code).is_some());
+
+        co_host
+            .handle_message(envelope(PeerMessage::EndMeeting))
+            .await
+            .unwrap();
+        assert!(state.find_room(&code).is_none());
+    }
+}
@@ -1,4 +1,4 @@
-use rand::Rng;
+use rand::{Rng, distr::Alphanumeric};
 
 pub fn is_valid(code: &str) -> bool {
     if code.len() != 11 {
@@ -9,22 +9,30 @@ pub fn is_valid(code: &str) -> bool {
             if c != b'-' {
                 return false;
             }
-        } else if c < b'a' || c > b'z' {
+        } else if !c.is_ascii_lowercase() {
             return false;
         }
     }
-    return true;
+    true
 }
 
 pub fn generate() -> String {
     let mut rng = rand::rng();
     let mut code = String::with_capacity(11);
     for i in 0..9 {
-        let c = rng.random_range(b'a'..b'z');
+        let c = rng.random_range(b'a'..=b'z');
         code.push(c as char);
         if i == 2 || i == 5 {
             code.push(b'-' as char);
         }
     }
-    return code;
+    code
+}
+
+pub fn generate_token() -> String {
+    rand::rng()
+        .sample_iter(Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
 }
@@ -0,0 +1,140 @@
+use std::{collections::HashSet, time::Duration};
+
+pub struct Limits {
+    /// Largest WebSocket frame or message the server accepts, in bytes.
+    pub max_frame_size: usize,
+    /// Longest display name, in characters.
+    pub max_name_len: usize,
+    /// Largest offer or answer SDP, in bytes.
+    pub max_sdp_len: usize,
+    /// How many remote ICE candidates a peer buffers until its remote description is set.
+    pub max_pending_candidates: usize,
+    /// Longest client version a join may carry, in bytes.
+    pub max_client_version_len: usize,
+    /// Most codecs or features a join may declare, each.
+    pub max_capabilities: usize,
+    /// Longest room passcode, in bytes.
+    pub max_passcode_len: usize,
+    /// Longest chat message, in characters.
+    pub max_chat_len: usize,
+    /// Most breakout rooms open off one room.
+    pub max_breakout_rooms: usize,
+    /// Longest room or scheduled meeting title, in characters.
+    pub max_title_len: usize,
+    /// Most invitees of a scheduled meeting.
+    pub max_invitees: usize,
+    /// Longest room description, in characters.
+    pub max_description_len: usize,
+    /// Largest set of room attributes, in bytes of JSON.
+    pub max_attributes_len: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_frame_size: 1024 * 1024,
+            max_name_len: 64,
+            max_sdp_len: 512 * 1024,
+            max_pending_candidates: 64,
+            max_client_version_len: 64,
+            max_capabilities: 32,
+            max_passcode_len: 64,
+            max_chat_len: 2000,
+            max_breakout_rooms: 50,
+            max_title_len: 200,
+            max_invitees: 100,
+            max_description_len: 2000,
+            max_attributes_len: 16 * 1024,
+        }
+    }
+}
+
+/// Caps on participants, counting publishers and viewers separately. `None` means no cap.
+#[derive(Default)]
+pub struct Capacity {
+    /// Publishers per room.
+    pub room_publishers: Option<usize>,
+    /// Viewers per room.
+    pub room_viewers: Option<usize>,
+    /// Publishers across all rooms.
+    pub publishers: Option<usize>,
+    /// Viewers across all rooms.
+    pub viewers: Option<usize>,
+}
+
+pub struct Config {
+    /// How long a peer is kept after its signaling socket drops, waiting to be resumed.
+    pub resume_grace: Duration,
+    /// How many sent signaling messages are kept per session for replay on resume.
+    pub resume_history: usize,
+    /// How long room changes are collected before the affected peers get a single offer.
+    pub offer_debounce: Duration,
+    /// How often the server pings the signaling socket.
+    pub ping_interval: Duration,
+    /// How long the signaling socket may stay silent, pongs included, before it counts as dropped.
+    pub ping_timeout: Duration,
+    /// How long a peer connection may stay new or connecting before the peer is evicted.
+    pub connect_timeout: Duration,
+    /// How long a peer connection may stay disconnected or failed before the peer is evicted.
+    pub disconnect_timeout: Duration,
+    /// Whether client messages are checked against the protocol's JSON Schema before
+    /// being deserialized, which gives more useful errors at some cost.
+    pub validate_messages: bool,
+    /// How long a new connection may take to send its hello, and then its join message.
+    pub join_timeout: Duration,
+    /// Tokens that authenticate a join.
+    pub auth_tokens: HashSet<String>,
+    /// Whether joining requires one of `auth_tokens`.
+    pub require_auth: bool,
+    /// Wrong passcodes a client may try before it's turned away.
+    pub max_passcode_attempts: u32,
+    /// How long a client that ran out of passcode attempts is turned away for.
+    pub passcode_lockout: Duration,
+    /// How long a code handed out by `/code`, or of a room everyone left, can open a room.
+    pub reservation_ttl: Duration,
+    /// Most codes reserved at once, by `/code` and scheduled meetings alike.
+    pub max_reservations: usize,
+    /// How long before its start a scheduled meeting can be joined.
+    pub early_join: Duration,
+    /// Where the web client is served, for the join links of scheduled meetings.
+    pub web_url: String,
+    /// Whether new rooms start with their waiting room on.
+    pub waiting_room: bool,
+    /// Whether authenticated joins skip waiting rooms.
+    pub admit_authenticated: bool,
+    /// How long before breakout rooms close the peers in them get a countdown.
+    pub breakout_countdown: Duration,
+    /// Hard limits on signaling input.
+    pub limits: Limits,
+    /// How many participants rooms and the server take.
+    pub capacity: Capacity,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            resume_grace: Duration::from_secs(30),
+            resume_history: 64,
+            offer_debounce: Duration::from_millis(200),
+            ping_interval: Duration::from_secs(10),
+            ping_timeout: Duration::from_secs(25),
+            connect_timeout: Duration::from_secs(30),
+            disconnect_timeout: Duration::from_secs(20),
+            validate_messages: false,
+            join_timeout: Duration::from_secs(10),
+            auth_tokens: HashSet::new(),
+            require_auth: false,
+            max_passcode_attempts: 5,
+            passcode_lockout: Duration::from_secs(5 * 60),
+            reservation_ttl: Duration::from_secs(24 * 60 * 60),
+            max_reservations: 10_000,
+            early_join: Duration::from_secs(10 * 60),
+            web_url: "http://localhost:5173".into(),
+            waiting_room: false,
+            admit_authenticated: false,
+            breakout_countdown: Duration::from_secs(30),
+            limits: Limits::default(),
+            capacity: Capacity::default(),
+        }
+    }
+}
@@ -1,36 +1,180 @@
+use axum::{
+    Json,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde_json::json;
 use tokio::sync::mpsc;
 
-use crate::{signal::ServerMessage, track::Track};
+use crate::{
+    signal::{ErrorCode, Outgoing, ServerMessage, ServerMessageError},
+    track::Track,
+};
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("serde error: {0}")]
     Serde(#[from] serde_json::Error),
 
+    #[error("messagepack encode error: {0}")]
+    MessagePackEncode(#[from] rmp_serde::encode::Error),
+
+    #[error("messagepack decode error: {0}")]
+    MessagePackDecode(#[from] rmp_serde::decode::Error),
+
+    #[error("invalid message at '{path}': {message}")]
+    Schema { path: String, message: String },
+
+    #[error("invalid join: {0}")]
+    Join(&'static str),
+
+    #[error("invalid meeting: {0}")]
+    Meeting(&'static str),
+
+    #[error("{0}")]
+    Unauthorized(&'static str),
+
+    #[error("not permitted to {0}")]
+    Forbidden(&'static str),
+
+    #[error("unknown breakout room {0}")]
+    UnknownBreakout(u32),
+
+    #[error("room full")]
+    RoomFull,
+
+    #[error("room locked")]
+    RoomLocked,
+
+    #[error("unknown room")]
+    UnknownRoom,
+
+    #[error("meeting not started")]
+    NotStarted,
+
+    #[error("{what} exceeds the limit of {limit}")]
+    Limit { what: &'static str, limit: usize },
+
+    #[error("unknown peer {0}")]
+    UnknownPeer(u32),
+
     #[error("axum error: {0}")]
     Axum(#[from] axum::Error),
 
     #[error("webrtc error: {0}")]
     Webrtc(#[from] webrtc::error::Error),
 
+    #[error("invalid sdp: {0}")]
+    Sdp(webrtc::error::Error),
+
+    #[error("invalid ice candidate: {0}")]
+    Candidate(webrtc::error::Error),
+
     #[error("signal send error: {0}")]
-    SignalSend(#[from] mpsc::error::SendError<ServerMessage>),
+    SignalSend(#[from] mpsc::error::SendError<Outgoing>),
 
     #[error("track send error: {0}")]
     TrackSend(#[from] mpsc::error::SendError<Track>),
 }
 
+impl Error {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::Serde(_)
+            | Error::MessagePackDecode(_)
+            | Error::Schema { .. }
+            | Error::Join(_)
+            | Error::Meeting(_)
+            | Error::UnknownPeer(_)
+            | Error::UnknownBreakout(_) => ErrorCode::BadMessage,
+            Error::Unauthorized(_) | Error::Forbidden(_) => ErrorCode::PermissionDenied,
+            Error::Sdp(_) => ErrorCode::BadSdp,
+            Error::Candidate(_) => ErrorCode::InvalidCandidate,
+            Error::RoomFull => ErrorCode::RoomFull,
+            Error::RoomLocked => ErrorCode::RoomLocked,
+            Error::UnknownRoom => ErrorCode::UnknownRoom,
+            Error::NotStarted => ErrorCode::NotStarted,
+            Error::Limit { .. } => ErrorCode::LimitExceeded,
+            _ => ErrorCode::Internal,
+        }
+    }
+
+    /// Fatal errors close the session, everything else is only reported to the client.
+    pub fn is_fatal(&self) -> bool {
+        self.code() == ErrorCode::Internal
+    }
+
+    /// The description shown to clients, which hides the details of internal errors.
+    pub fn client_message(&self) -> String {
+        if self.code() == ErrorCode::Internal {
+            "internal error".into()
+        } else {
+            self.to_string()
+        }
+    }
+
+    pub fn to_message(&self, id: Option<u32>) -> ServerMessage {
+        ServerMessage::Error(ServerMessageError {
+            id,
+            code: self.code(),
+            message: self.client_message(),
+        })
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self.code() {
+            ErrorCode::BadMessage | ErrorCode::BadSdp | ErrorCode::InvalidCandidate => {
+                StatusCode::BAD_REQUEST
+            }
+            ErrorCode::PermissionDenied => StatusCode::FORBIDDEN,
+            ErrorCode::RoomFull => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::RoomLocked => StatusCode::LOCKED,
+            ErrorCode::UnknownRoom => StatusCode::NOT_FOUND,
+            ErrorCode::NotStarted => StatusCode::TOO_EARLY,
+            ErrorCode::LimitExceeded => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Lets HTTP handlers return errors the same way the signaling socket reports them.
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        println!("{}", self);
+        (
+            self.status(),
+            Json(json!({"error": self.client_message(), "code": self.code()})),
+        )
+            .into_response()
+    }
+}
+
 pub type Result<T> = std::result::Result<T, Error>;
 
+pub fn check_limit(what: &'static str, len: usize, limit: usize) -> Result<()> {
+    if len > limit {
+        return Err(Error::Limit { what, limit });
+    }
+    Ok(())
+}
+
 #[derive(Clone)]
 pub struct Sender {
     tx: mpsc::Sender<Error>,
 }
 
 impl Sender {
+    /// Reports the future's error to the session. Once the session is torn down
+    /// nobody is listening anymore, and the error is only logged.
     pub async fn send(&self, fut: impl Future<Output = Result<()>>) {
         if let Err(e) = fut.await {
-            self.tx.send(e).await.unwrap();
+            self.report(e).await;
+        }
+    }
+
+    pub async fn report(&self, e: Error) {
+        if let Err(e) = self.tx.send(e).await {
+            println!("{}", e.0);
         }
     }
 
@@ -53,5 +197,5 @@ impl Receiver {
 
 pub fn channel() -> (Sender, Receiver) {
     let (tx, rx) = mpsc::channel(4);
-    return (Sender { tx }, Receiver { rx });
+    (Sender { tx }, Receiver { rx })
 }
@@ -0,0 +1,20 @@
+pub mod breakout;
+pub mod code;
+pub mod config;
+pub mod error;
+pub mod metrics;
+pub mod passcode;
+pub mod peer;
+pub mod room;
+pub mod schedule;
+pub mod schema;
+pub mod server;
+pub mod session;
+pub mod signal;
+pub mod sse;
+pub mod state;
+#[cfg(test)]
+mod testing;
+pub mod track;
+pub mod whep;
+pub mod whip;
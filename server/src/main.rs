@@ -1,17 +1,7 @@
-use crate::server::Server;
-
-mod code;
-mod error;
-mod peer;
-mod room;
-mod server;
-mod session;
-mod signal;
-mod state;
-mod track;
+use zoom_server::{config::Config, server::Server};
 
 #[tokio::main]
 async fn main() {
-    let server = Server::new("127.0.0.1:3000");
+    let server = Server::new("127.0.0.1:3000", Config::default());
     server.run().await;
 }
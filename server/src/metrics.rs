@@ -0,0 +1,40 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::Serialize;
+
+use crate::room::Occupancy;
+
+#[derive(Default)]
+pub struct Metrics {
+    offers_requested: AtomicU64,
+    offers_sent: AtomicU64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MetricsSnapshot {
+    pub offers_requested: u64,
+    pub offers_sent: u64,
+    pub offers_saved: u64,
+    pub occupancy: Occupancy,
+}
+
+impl Metrics {
+    /// Records one offer sent in place of `requested` renegotiation requests.
+    pub fn record_offer(&self, requested: u32) {
+        self.offers_requested
+            .fetch_add(requested as u64, Ordering::Relaxed);
+        self.offers_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self, occupancy: Occupancy) -> MetricsSnapshot {
+        let offers_requested = self.offers_requested.load(Ordering::Relaxed);
+        let offers_sent = self.offers_sent.load(Ordering::Relaxed);
+        MetricsSnapshot {
+            offers_requested,
+            offers_sent,
+            offers_saved: offers_requested.saturating_sub(offers_sent),
+            occupancy,
+        }
+    }
+}
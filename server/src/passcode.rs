@@ -0,0 +1,50 @@
+use std::{num::NonZeroU32, sync::Arc};
+
+use rand::Rng;
+use ring::pbkdf2;
+use tokio::task;
+
+const ALGORITHM: pbkdf2::Algorithm = pbkdf2::PBKDF2_HMAC_SHA256;
+
+const ITERATIONS: NonZeroU32 = NonZeroU32::new(100_000).unwrap();
+
+/// A room's passcode, of which only a salted hash is kept. Hashing is slow on purpose,
+/// so it is done on the blocking pool.
+pub struct Passcode {
+    salt: [u8; 16],
+    hash: [u8; 32],
+}
+
+impl Passcode {
+    pub async fn hash(passcode: String) -> Self {
+        task::spawn_blocking(move || Self::new(&passcode))
+            .await
+            .unwrap()
+    }
+
+    fn new(passcode: &str) -> Self {
+        let mut salt = [0; 16];
+        rand::rng().fill(&mut salt);
+        let mut hash = [0; 32];
+        pbkdf2::derive(ALGORITHM, ITERATIONS, &salt, passcode.as_bytes(), &mut hash);
+        Self { salt, hash }
+    }
+
+    /// Compares in constant time.
+    pub async fn verify(self: Arc<Self>, passcode: String) -> bool {
+        task::spawn_blocking(move || self.matches(&passcode))
+            .await
+            .unwrap()
+    }
+
+    fn matches(&self, passcode: &str) -> bool {
+        pbkdf2::verify(
+            ALGORITHM,
+            ITERATIONS,
+            &self.salt,
+            passcode.as_bytes(),
+            &self.hash,
+        )
+        .is_ok()
+    }
+}
@@ -1,11 +1,12 @@
-use std::{pin::Pin, sync::Arc};
+use std::{collections::HashSet, net::IpAddr, pin::Pin, sync::Arc};
 
 use once_cell::sync::Lazy;
+use tokio::sync::mpsc;
 use webrtc::{
     api::{APIBuilder, media_engine::MediaEngine},
     ice_transport::ice_candidate::RTCIceCandidateInit,
     peer_connection::{
-        RTCPeerConnection, configuration::RTCConfiguration,
+        RTCPeerConnection, configuration::RTCConfiguration, offer_answer_options::RTCOfferOptions,
         peer_connection_state::RTCPeerConnectionState,
         sdp::session_description::RTCSessionDescription, signaling_state::RTCSignalingState,
     },
@@ -21,8 +22,12 @@ use webrtc::{
 };
 
 use crate::{
-    error::Result,
-    signal::{self, ServerMessage},
+    config::Config,
+    error::{self, Error, Result, check_limit},
+    room,
+    signal::{
+        self, Capabilities, Join, MediaKind, Role, RoomMetadata, ServerMessage, ServerMessagePeer,
+    },
     track::Track,
 };
 
@@ -31,24 +36,171 @@ static MEDIA_ENGINE_MUTEX: Lazy<std::sync::Mutex<()>> = Lazy::new(|| std::sync::
 pub struct PeerTrack {
     pub inner: Arc<TrackLocalStaticRTP>,
     pub ssrc: u32,
+    /// A video track published after the peer's camera.
+    pub screen: bool,
+}
+
+/// Who a peer is, as established by its join.
+pub struct Profile {
+    pub name: String,
+    pub client_version: String,
+    pub capabilities: Capabilities,
+    /// The join carried one of the configured auth tokens.
+    pub authenticated: bool,
+}
+
+impl Profile {
+    /// Validates a join, which has to pass before the peer is created.
+    pub fn from_join(join: Join, config: &Config) -> Result<Self> {
+        let limits = &config.limits;
+        if join.name.trim().is_empty() {
+            return Err(Error::Join("empty name"));
+        }
+        check_limit("name", join.name.chars().count(), limits.max_name_len)?;
+        check_limit(
+            "client version",
+            join.client_version.len(),
+            limits.max_client_version_len,
+        )?;
+        let capabilities = &join.capabilities;
+        if let Some(codecs) = &capabilities.codecs {
+            check_limit("codecs", codecs.len(), limits.max_capabilities)?;
+        }
+        check_limit(
+            "features",
+            capabilities.features.len(),
+            limits.max_capabilities,
+        )?;
+        let authenticated = match &join.token {
+            Some(token) if config.auth_tokens.contains(token) => true,
+            Some(_) => return Err(Error::Unauthorized("invalid auth token")),
+            None if config.require_auth => {
+                return Err(Error::Unauthorized("authentication required"));
+            }
+            None => false,
+        };
+        Ok(Self {
+            name: join.name,
+            client_version: join.client_version,
+            capabilities: join.capabilities,
+            authenticated,
+        })
+    }
+}
+
+/// A validated join: who the peer is, and what it presents to get into the room.
+pub struct Admission {
+    pub profile: Profile,
+    /// Checked against the room's passcode. Not kept.
+    pub passcode: Option<String>,
+    /// Becomes the metadata of a new room. Ignored otherwise.
+    pub room: Option<RoomMetadata>,
+    /// Address the client connects from, which failed passcodes count against.
+    pub client: IpAddr,
+    /// A moderator let the join in from the lobby.
+    pub admitted: bool,
+    /// The passcode was checked when the join knocked.
+    pub passcode_checked: bool,
+}
+
+impl Admission {
+    pub fn from_join(mut join: Join, client: IpAddr, config: &Config) -> Result<Self> {
+        let passcode = join.passcode.take();
+        if let Some(passcode) = &passcode {
+            check_limit("passcode", passcode.len(), config.limits.max_passcode_len)?;
+        }
+        let room = join.room.take();
+        if let Some(room) = &room {
+            room::check_metadata(room, &config.limits)?;
+        }
+        Ok(Self {
+            profile: Profile::from_join(join, config)?,
+            passcode,
+            room,
+            client,
+            admitted: false,
+            passcode_checked: false,
+        })
+    }
+}
+
+/// How a peer takes part in its room.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// A `/signal` client that publishes and receives everyone else's tracks.
+    Signal,
+    /// A WHIP client that only publishes.
+    Publish,
+    /// A WHEP client that only receives, either everyone's tracks or those of one peer.
+    View(Option<u32>),
+}
+
+impl Mode {
+    pub fn publishes(self) -> bool {
+        matches!(self, Mode::Signal | Mode::Publish)
+    }
+
+    /// Whether the peer gets the tracks that `peer_id` publishes.
+    pub fn receives_from(self, peer_id: u32) -> bool {
+        match self {
+            Mode::Signal | Mode::View(None) => true,
+            Mode::Publish => false,
+            Mode::View(Some(id)) => id == peer_id,
+        }
+    }
+
+    /// Whether the server can send the peer offers. HTTP peers negotiate once.
+    pub fn renegotiates(self) -> bool {
+        matches!(self, Mode::Signal)
+    }
+}
+
+/// Server side of the perfect negotiation pattern. The server is always the impolite
+/// side, since webrtc-rs can't roll back a local offer (it has no transition from
+/// have-local-offer to stable, see `local_offer_cannot_be_rolled_back`): on glare
+/// clients roll back theirs, as the web client does.
+#[derive(Default)]
+pub struct Negotiation {
+    /// Renegotiations requested since the last offer, sent as one when the room flushes.
+    pub requested: u32,
+    /// A renegotiation was requested while another one was in flight.
+    pub needed: bool,
+    /// The last remote offer collided with ours and was ignored.
+    pub ignore_offer: bool,
+    /// Id of the offer we are waiting an answer for.
+    pub offer_id: Option<u32>,
+    /// The next offer restarts ICE.
+    pub ice_restart: bool,
 }
 
 pub struct Peer {
     pub id: u32,
     pub room_id: u32,
+    pub mode: Mode,
+    pub role: Role,
+    /// Tracks a moderator stopped forwarding.
+    pub muted: HashSet<MediaKind>,
     pub conn: RTCPeerConnection,
     pub signal_tx: signal::Sender,
-    pub name: Option<String>,
+    /// Reports errors from work done on the peer's behalf outside its session.
+    pub error_tx: error::Sender,
+    pub profile: Profile,
     pub video: Option<PeerTrack>,
+    pub screen: Option<PeerTrack>,
     pub audio: Option<PeerTrack>,
     pub pending_candidates: Vec<RTCIceCandidateInit>,
+    pub negotiation: Negotiation,
 }
 
-pub type OnPeerConnectedHdlrFn =
-    Box<dyn (Fn() -> Pin<Box<dyn Future<Output = ()> + Send + 'static>>) + Send + Sync>;
+pub type OnPeerStateHdlrFn = Box<
+    dyn (Fn(RTCPeerConnectionState) -> Pin<Box<dyn Future<Output = ()> + Send + 'static>>)
+        + Send
+        + Sync,
+>;
 
+/// Called with `None` once gathering is complete.
 pub type OnPeerCandidateHdlrFn = Box<
-    dyn (Fn(RTCIceCandidateInit) -> Pin<Box<dyn Future<Output = ()> + Send + 'static>>)
+    dyn (Fn(Option<RTCIceCandidateInit>) -> Pin<Box<dyn Future<Output = ()> + Send + 'static>>)
         + Send
         + Sync,
 >;
@@ -58,7 +210,15 @@ pub type OnPeerTrackHdlrFn = Box<
 >;
 
 impl Peer {
-    pub async fn new(id: u32, room_id: u32, signal_tx: signal::Sender) -> Result<Self> {
+    pub async fn new(
+        id: u32,
+        room_id: u32,
+        mode: Mode,
+        role: Role,
+        profile: Profile,
+        signal_tx: signal::Sender,
+        error_tx: error::Sender,
+    ) -> Result<Self> {
         let api = APIBuilder::new()
             .with_media_engine({
                 let mut engine = MediaEngine::default();
@@ -69,18 +229,26 @@ impl Peer {
             .build();
         let config = RTCConfiguration::default();
         let conn = api.new_peer_connection(config).await?;
-        let mut peer = Self {
+        let peer = Self {
             id,
             room_id,
+            mode,
+            role,
+            muted: HashSet::new(),
             conn,
             signal_tx,
-            name: None,
+            error_tx,
+            profile,
             video: None,
+            screen: None,
             audio: None,
             pending_candidates: Vec::new(),
+            negotiation: Negotiation::default(),
         };
-        peer.signal_tx.send(ServerMessage::Id(id)).await?;
-        peer.debug("new peer");
+        peer.debug(&format!(
+            "new peer {} ({})",
+            peer.profile.name, peer.profile.client_version
+        ));
         Ok(peer)
     }
 
@@ -100,38 +268,99 @@ impl Peer {
         }));
     }
 
-    pub fn on_connected(&self, f: OnPeerConnectedHdlrFn) {
-        let message = self.debug_format("connection established");
+    pub fn on_state(&self, f: OnPeerStateHdlrFn) {
+        let message = self.debug_format("connection");
         self.conn
             .on_peer_connection_state_change(Box::new(move |state| {
-                if state != RTCPeerConnectionState::Connected {
-                    return Box::pin(async {});
-                }
-                println!("{}", message);
-                f()
+                println!("{} {}", message, state);
+                f(state)
             }));
     }
 
     pub fn on_candidate(&self, f: OnPeerCandidateHdlrFn) {
-        let message = self.debug_format("new local ice candidate");
+        let message = self.debug_format("local ice candidate gathered");
         self.conn.on_ice_candidate(Box::new(move |candidate| {
-            if let Some(candidate) = candidate {
-                println!("{}", message);
-                f(candidate.to_json().unwrap())
-            } else {
-                Box::pin(async {})
-            }
+            println!("{}", message);
+            f(candidate.map(|candidate| candidate.to_json().unwrap()))
         }));
     }
 
     async fn set_remote_description(&mut self, description: RTCSessionDescription) -> Result<()> {
-        self.conn.set_remote_description(description).await?;
+        self.conn
+            .set_remote_description(description)
+            .await
+            .map_err(Error::Sdp)?;
         for candidate in self.pending_candidates.drain(..) {
-            self.conn.add_ice_candidate(candidate).await?;
+            self.conn
+                .add_ice_candidate(candidate)
+                .await
+                .map_err(Error::Candidate)?;
         }
         Ok(())
     }
 
+    pub fn info(&self) -> ServerMessagePeer {
+        ServerMessagePeer {
+            id: self.id,
+            name: self.profile.name.clone(),
+            capabilities: self.profile.capabilities.clone(),
+            role: self.role,
+            permissions: self.role.permissions(),
+        }
+    }
+
+    /// Whether the peer's role lets the track be forwarded.
+    pub fn may_publish(&self, track: &PeerTrack) -> bool {
+        let permissions = self.role.permissions();
+        match track.inner.kind() {
+            RTPCodecType::Audio => permissions.publish_audio,
+            RTPCodecType::Video if track.screen => permissions.screen_share,
+            RTPCodecType::Video => permissions.publish_video,
+            _ => false,
+        }
+    }
+
+    /// Whether the track is forwarded to others. Muting video stops the screen share too.
+    pub fn forwards(&self, track: &PeerTrack) -> bool {
+        let kind = track.inner.kind();
+        self.may_publish(track) && !self.muted.iter().any(|&muted| kind == muted.into())
+    }
+
+    /// The peer's tracks, as far as they arrived.
+    pub fn tracks(&self) -> impl Iterator<Item = &PeerTrack> {
+        [&self.video, &self.screen, &self.audio]
+            .into_iter()
+            .flatten()
+    }
+
+    /// Whether the track can be offered to the peer: the client declared it decodes
+    /// the track's codec and, for video, has a decode to spare. Tracks the peer is
+    /// already sent are left out.
+    pub async fn accepts_track(&self, track: &TrackLocalStaticRTP) -> bool {
+        let capabilities = &self.profile.capabilities;
+        if !capabilities.decodes(&track.codec().mime_type) {
+            return false;
+        }
+        let mut video_decodes = 0;
+        for transceiver in self.conn.get_transceivers().await {
+            if transceiver.direction() != RTCRtpTransceiverDirection::Sendonly {
+                continue;
+            }
+            if let Some(sent) = transceiver.sender().await.track().await
+                && sent.id() == track.id()
+            {
+                return false;
+            }
+            if transceiver.kind() == RTPCodecType::Video {
+                video_decodes += 1;
+            }
+        }
+        track.kind() != RTPCodecType::Video
+            || capabilities
+                .max_video_decodes
+                .is_none_or(|max| video_decodes < max)
+    }
+
     pub fn is_audio_and_video(&self) -> bool {
         self.audio.is_some() && self.video.is_some()
     }
@@ -164,30 +393,29 @@ impl Peer {
         Ok(())
     }
 
-    pub async fn stop_transceivers(&self, peer_id: u32) -> Result<()> {
-        let peer_id_str = peer_id.to_string();
+    /// Stops sending the tracks of `peer_id`, only the one with `track_id` if given.
+    pub async fn stop_transceivers(&self, peer_id: u32, track_id: Option<&str>) -> Result<()> {
+        let prefix = format!("{}-", peer_id);
         for transceiver in self.conn.get_transceivers().await {
-            if let Some(track) = transceiver.sender().await.track().await {
-                if track.id().starts_with(&peer_id_str) {
-                    transceiver.stop().await?;
-                    self.debug(&format!(
-                        "peer {} {} transceiver stopped",
-                        peer_id,
-                        track.kind()
-                    ));
-                }
+            if let Some(track) = transceiver.sender().await.track().await
+                && track.id().starts_with(&prefix)
+                && track_id.is_none_or(|id| track.id() == id)
+            {
+                transceiver.stop().await?;
+                self.debug(&format!(
+                    "peer {} {} transceiver stopped",
+                    peer_id,
+                    track.kind()
+                ));
             }
         }
         Ok(())
     }
 
-    pub fn set_name(&mut self, name: String) {
-        self.name = Some(name)
-    }
-
     pub fn set_track(&mut self, track: Track) {
         match track.kind {
             RTPCodecType::Audio => self.audio = Some(track.inner),
+            RTPCodecType::Video if track.inner.screen => self.screen = Some(track.inner),
             RTPCodecType::Video => self.video = Some(track.inner),
             _ => {}
         }
@@ -210,43 +438,146 @@ impl Peer {
         Ok(())
     }
 
+    /// Marks the peer for renegotiation; the offer goes out with the next `Room::send_offers`.
+    pub fn request_offer(&mut self) {
+        if !self.mode.renegotiates() {
+            return;
+        }
+        self.negotiation.requested += 1;
+    }
+
+    /// Starts a renegotiation, or queues one if an offer is already in flight.
     pub async fn send_offer(&mut self) -> Result<()> {
-        let offer = self.conn.create_offer(None).await?;
+        if !self.mode.renegotiates() {
+            return Ok(());
+        }
+        if self.conn.signaling_state() != RTCSignalingState::Stable {
+            self.negotiation.needed = true;
+            self.debug("negotiation queued");
+            return Ok(());
+        }
+        self.negotiation.needed = false;
+        let options = RTCOfferOptions {
+            ice_restart: std::mem::take(&mut self.negotiation.ice_restart),
+            ..Default::default()
+        };
+        let offer = self.conn.create_offer(Some(options)).await?;
         self.conn.set_local_description(offer.clone()).await?;
-        self.signal_tx.send(ServerMessage::Offer(offer.sdp)).await?;
+        let id = self
+            .signal_tx
+            .request(ServerMessage::Offer(offer.sdp))
+            .await?;
+        self.negotiation.offer_id = Some(id);
         self.debug("offer sent");
         Ok(())
     }
 
-    pub async fn recv_offer(&mut self, sdp: String) -> Result<()> {
-        if self.conn.signaling_state() != RTCSignalingState::Stable {
-            self.debug("signaling state not stable");
+    /// Sends an offer with fresh ICE credentials, or queues one if an offer is in flight.
+    /// Does nothing before the first negotiation.
+    pub async fn restart_ice(&mut self) -> Result<()> {
+        if !self.mode.renegotiates()
+            || self.conn.remote_description().await.is_none()
+            || self.negotiation.ice_restart
+        {
+            return Ok(());
+        }
+        self.debug("ice restart");
+        self.negotiation.ice_restart = true;
+        self.send_offer().await
+    }
+
+    /// Username fragment of the current local ICE generation.
+    pub async fn local_ice_ufrag(&self) -> Option<String> {
+        let description = self.conn.local_description().await?;
+        description
+            .sdp
+            .lines()
+            .find_map(|line| line.strip_prefix("a=ice-ufrag:"))
+            .map(|ufrag| ufrag.trim().to_string())
+    }
+
+    async fn send_queued_offer(&mut self) -> Result<()> {
+        if self.negotiation.needed {
+            self.send_offer().await?;
+        }
+        Ok(())
+    }
+
+    pub async fn recv_offer(&mut self, id: u32, sdp: String) -> Result<()> {
+        self.negotiation.ignore_offer = self.conn.signaling_state() != RTCSignalingState::Stable;
+        if self.negotiation.ignore_offer {
+            self.debug("colliding offer ignored");
             return Ok(());
         }
         self.debug("offer received");
-        self.set_remote_description(RTCSessionDescription::offer(sdp)?)
+        self.set_remote_description(RTCSessionDescription::offer(sdp).map_err(Error::Sdp)?)
             .await?;
         let answer = self.conn.create_answer(None).await?;
         self.conn.set_local_description(answer.clone()).await?;
         self.signal_tx
-            .send(ServerMessage::Answer(answer.sdp))
+            .reply(id, ServerMessage::Answer(answer.sdp))
             .await?;
         self.debug("answer sent");
-        Ok(())
+        self.send_queued_offer().await
+    }
+
+    /// Answers an offer that arrived outside of signaling, as with WHIP. Since no
+    /// candidates can follow the answer, it is only complete once the returned receiver
+    /// says ICE gathering is, and is then read with `complete_answer`.
+    pub async fn accept_offer(&mut self, sdp: String) -> Result<mpsc::Receiver<()>> {
+        self.debug("offer received");
+        self.set_remote_description(RTCSessionDescription::offer(sdp).map_err(Error::Sdp)?)
+            .await?;
+        let answer = self.conn.create_answer(None).await?;
+        let gathering_complete = self.conn.gathering_complete_promise().await;
+        self.conn.set_local_description(answer).await?;
+        Ok(gathering_complete)
     }
 
-    pub async fn recv_answer(&mut self, sdp: String) -> Result<()> {
+    /// The answer made by `accept_offer`, with the candidates gathered since.
+    pub async fn complete_answer(&self) -> Result<String> {
+        let description = self
+            .conn
+            .local_description()
+            .await
+            .ok_or(webrtc::Error::ErrConnectionClosed)?;
+        self.debug("answer sent");
+        Ok(description.sdp)
+    }
+
+    pub async fn recv_answer(&mut self, re: Option<u32>, sdp: String) -> Result<()> {
+        if self.conn.signaling_state() != RTCSignalingState::HaveLocalOffer
+            || (re.is_some() && re != self.negotiation.offer_id)
+        {
+            self.debug("stale answer ignored");
+            return Ok(());
+        }
         self.debug("answer received");
-        self.set_remote_description(RTCSessionDescription::answer(sdp)?)
+        self.set_remote_description(RTCSessionDescription::answer(sdp).map_err(Error::Sdp)?)
             .await?;
-        Ok(())
+        self.negotiation.offer_id = None;
+        self.send_queued_offer().await
     }
 
-    pub async fn add_candidate(&mut self, candidate: RTCIceCandidateInit) -> Result<()> {
+    pub async fn add_candidate(
+        &mut self,
+        candidate: RTCIceCandidateInit,
+        max_pending: usize,
+    ) -> Result<()> {
         self.debug("new remote ice candidate");
         if self.conn.remote_description().await.is_some() {
-            self.conn.add_ice_candidate(candidate).await?;
+            let result = self.conn.add_ice_candidate(candidate).await;
+            if let Err(e) = result
+                && !self.negotiation.ignore_offer
+            {
+                return Err(Error::Candidate(e));
+            }
         } else {
+            check_limit(
+                "pending ice candidates",
+                self.pending_candidates.len() + 1,
+                max_pending,
+            )?;
             self.pending_candidates.push(candidate);
         }
         Ok(())
@@ -258,3 +589,188 @@ impl Peer {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use webrtc::{
+        api::media_engine::{MIME_TYPE_OPUS, MIME_TYPE_VP8},
+        peer_connection::sdp::sdp_type::RTCSdpType,
+        rtp_transceiver::rtp_codec::RTCRtpCodecCapability,
+    };
+
+    use super::*;
+    use crate::testing::profile;
+
+    async fn peer(role: Role) -> Peer {
+        let signal_tx = signal::Sender::new(&Config::default());
+        let (error_tx, _error_rx) = error::channel();
+        Peer::new(
+            1,
+            1,
+            Mode::Signal,
+            role,
+            profile("test"),
+            signal_tx,
+            error_tx,
+        )
+        .await
+        .unwrap()
+    }
+
+    fn track(mime_type: &str, id: &str, screen: bool) -> PeerTrack {
+        let capability = RTCRtpCodecCapability {
+            mime_type: mime_type.into(),
+            ..Default::default()
+        };
+        PeerTrack {
+            inner: Arc::new(TrackLocalStaticRTP::new(
+                capability,
+                id.into(),
+                "stream".into(),
+            )),
+            ssrc: 0,
+            screen,
+        }
+    }
+
+    /// A webrtc-rs peer connection standing in for the client.
+    async fn client() -> RTCPeerConnection {
+        let mut engine = MediaEngine::default();
+        engine.register_default_codecs().unwrap();
+        let api = APIBuilder::new().with_media_engine(engine).build();
+        api.new_peer_connection(RTCConfiguration::default())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn colliding_client_offer_is_ignored() {
+        let mut peer = peer(Role::Host).await;
+        peer.add_recvonly_transceiver(RTPCodecType::Audio)
+            .await
+            .unwrap();
+        peer.send_offer().await.unwrap();
+
+        let client = client().await;
+        client
+            .add_transceiver_from_kind(RTPCodecType::Audio, None)
+            .await
+            .unwrap();
+        let offer = client.create_offer(None).await.unwrap();
+
+        peer.recv_offer(1, offer.sdp).await.unwrap();
+        assert!(peer.negotiation.ignore_offer);
+        assert_eq!(
+            peer.conn.signaling_state(),
+            RTCSignalingState::HaveLocalOffer
+        );
+        assert!(peer.conn.remote_description().await.is_none());
+    }
+
+    /// Why the server can't be the polite side: webrtc-rs has no transition from
+    /// have-local-offer back to stable, so a local offer can't be rolled back on glare.
+    #[tokio::test]
+    async fn local_offer_cannot_be_rolled_back() {
+        let mut peer = peer(Role::Host).await;
+        peer.add_recvonly_transceiver(RTPCodecType::Audio)
+            .await
+            .unwrap();
+        peer.send_offer().await.unwrap();
+
+        // An empty rollback would be refused before the state is looked at, so this
+        // one carries the offer.
+        let offer = peer.conn.local_description().await.unwrap();
+        let rollback = serde_json::json!({"type": "rollback", "sdp": offer.sdp});
+        let rollback: RTCSessionDescription = serde_json::from_value(rollback).unwrap();
+        assert!(matches!(
+            peer.conn.set_local_description(rollback).await,
+            Err(webrtc::Error::ErrSignalingStateProposedTransitionInvalid {
+                from: RTCSignalingState::HaveLocalOffer,
+                applying: RTCSdpType::Rollback,
+                is_local: true,
+            })
+        ));
+        assert_eq!(
+            peer.conn.signaling_state(),
+            RTCSignalingState::HaveLocalOffer
+        );
+    }
+
+    #[tokio::test]
+    async fn screen_share_takes_its_own_permission() {
+        let camera = track(MIME_TYPE_VP8, "1-video", false);
+        let screen = track(MIME_TYPE_VP8, "1-screen", true);
+        let audio = track(MIME_TYPE_OPUS, "1-audio", false);
+
+        let mut peer = peer(Role::Participant).await;
+        assert!(peer.forwards(&camera));
+        assert!(!peer.forwards(&screen));
+        assert!(peer.forwards(&audio));
+
+        peer.role = Role::CoHost;
+        assert!(peer.forwards(&screen));
+        peer.muted.insert(MediaKind::Video);
+        assert!(!peer.forwards(&camera));
+        assert!(!peer.forwards(&screen));
+        assert!(peer.forwards(&audio));
+
+        peer.role = Role::Viewer;
+        peer.muted.clear();
+        for track in [camera, screen, audio] {
+            assert!(!peer.forwards(&track));
+        }
+    }
+
+    #[tokio::test]
+    async fn ice_restart_offers_new_credentials() {
+        let mut peer = peer(Role::Host).await;
+        peer.restart_ice().await.unwrap();
+        assert!(peer.conn.local_description().await.is_none());
+
+        peer.add_recvonly_transceiver(RTPCodecType::Audio)
+            .await
+            .unwrap();
+        peer.send_offer().await.unwrap();
+        let offer = peer.conn.local_description().await.unwrap();
+        let client = client().await;
+        client.set_remote_description(offer).await.unwrap();
+        let answer = client.create_answer(None).await.unwrap();
+        peer.recv_answer(peer.negotiation.offer_id, answer.sdp)
+            .await
+            .unwrap();
+        let ufrag = peer.local_ice_ufrag().await.unwrap();
+
+        peer.restart_ice().await.unwrap();
+        assert_eq!(
+            peer.conn.signaling_state(),
+            RTCSignalingState::HaveLocalOffer
+        );
+        assert!(!peer.negotiation.ice_restart);
+        assert_ne!(peer.local_ice_ufrag().await.unwrap(), ufrag);
+    }
+
+    #[tokio::test]
+    async fn tracks_are_offered_as_far_as_capabilities_go() {
+        let camera = track(MIME_TYPE_VP8, "2-video", false);
+        let screen = track(MIME_TYPE_VP8, "2-screen", true);
+        let audio = track(MIME_TYPE_OPUS, "2-audio", false);
+
+        let mut peer = peer(Role::Participant).await;
+        peer.profile.capabilities = Capabilities {
+            codecs: Some(vec!["AUDIO/OPUS".into()]),
+            ..Capabilities::default()
+        };
+        assert!(peer.accepts_track(&audio.inner).await);
+        assert!(!peer.accepts_track(&camera.inner).await);
+
+        peer.profile.capabilities = Capabilities {
+            max_video_decodes: Some(1),
+            ..Capabilities::default()
+        };
+        assert!(peer.accepts_track(&camera.inner).await);
+        peer.add_sendonly_transceiver(&camera.inner).await.unwrap();
+        assert!(!peer.accepts_track(&camera.inner).await);
+        assert!(!peer.accepts_track(&screen.inner).await);
+        assert!(peer.accepts_track(&audio.inner).await);
+    }
+}
@@ -1,31 +1,148 @@
-use std::sync::{
-    Arc,
-    atomic::{AtomicU32, Ordering},
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU32, Ordering},
+    },
+    time::{Instant, SystemTime, UNIX_EPOCH},
 };
 
+use serde::Serialize;
+use tokio::sync::{Mutex, oneshot};
 use webrtc::{
     peer_connection::peer_connection_state::RTCPeerConnectionState,
-    track::track_local::track_local_static_rtp::TrackLocalStaticRTP,
+    track::track_local::{TrackLocal, track_local_static_rtp::TrackLocalStaticRTP},
 };
 
 use crate::{
-    error::Result,
-    peer::Peer,
-    signal::{self, ServerMessage, ServerMessagePeer},
+    config::{Config, Limits},
+    error::{self, Error, Result, check_limit},
+    metrics::Metrics,
+    passcode::Passcode,
+    peer::{Admission, Mode, Peer, Profile},
+    signal::{
+        self, Role, RoomMetadata, ServerMessage, ServerMessageBreakouts, ServerMessageChat,
+        ServerMessageKnock, ServerMessageMute, ServerMessagePeer, ServerMessageRoom,
+    },
 };
 
+/// How many participants take part, with viewers counted apart from publishers.
+#[derive(Serialize, Clone, Copy, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Occupancy {
+    pub publishers: usize,
+    pub viewers: usize,
+}
+
+impl Occupancy {
+    pub fn of(&self, mode: Mode) -> usize {
+        if mode.publishes() {
+            self.publishers
+        } else {
+            self.viewers
+        }
+    }
+}
+
+/// A join waiting in the lobby for a moderator's decision.
+pub struct Knock {
+    pub id: u32,
+    pub name: String,
+    pub since: SystemTime,
+    decision: oneshot::Sender<bool>,
+}
+
+impl Knock {
+    fn info(&self) -> ServerMessageKnock {
+        let since = self.since.duration_since(UNIX_EPOCH).unwrap_or_default();
+        ServerMessageKnock {
+            id: self.id,
+            name: self.name.clone(),
+            since: since.as_millis() as u64,
+        }
+    }
+}
+
+/// Breakout rooms opened off a main room, all of which close together.
+pub struct Breakouts {
+    pub names: Vec<String>,
+    pub rooms: Vec<Arc<Mutex<Room>>>,
+    pub ends: Instant,
+}
+
+impl Breakouts {
+    pub fn info(&self) -> ServerMessageBreakouts {
+        let left = self.ends.saturating_duration_since(Instant::now());
+        ServerMessageBreakouts {
+            names: self.names.clone(),
+            seconds: left.as_secs_f32().round() as u32,
+        }
+    }
+}
+
+/// Checks metadata from a client against the configured limits.
+pub fn check_metadata(metadata: &RoomMetadata, limits: &Limits) -> Result<()> {
+    if let Some(title) = &metadata.title {
+        check_limit("room title", title.chars().count(), limits.max_title_len)?;
+    }
+    if let Some(description) = &metadata.description {
+        check_limit(
+            "room description",
+            description.chars().count(),
+            limits.max_description_len,
+        )?;
+    }
+    let attributes = serde_json::to_string(&metadata.attributes).unwrap_or_default();
+    check_limit(
+        "room attributes",
+        attributes.len(),
+        limits.max_attributes_len,
+    )
+}
+
 pub struct Room {
     next_peer_id: Arc<AtomicU32>,
+    metrics: Arc<Metrics>,
     pub id: u32,
     pub peers: Vec<Peer>,
+    pub metadata: RoomMetadata,
+    pub created: SystemTime,
+    /// Name of the peer that opened the room.
+    pub creator: Option<String>,
+    pub offers_scheduled: bool,
+    /// Set when the room's code was reserved.
+    pub passcode: Option<Arc<Passcode>>,
+    /// Whether joins wait in the lobby until a moderator admits them.
+    pub waiting_room: bool,
+    pub lobby: Vec<Knock>,
+    /// Whether new joins are turned away.
+    pub locked: bool,
+    /// The index of a breakout room in its main room's.
+    pub breakout: Option<u32>,
+    pub breakouts: Option<Breakouts>,
 }
 
 impl Room {
-    pub fn new(id: u32, next_peer_id: Arc<AtomicU32>) -> Self {
+    pub fn new(
+        id: u32,
+        next_peer_id: Arc<AtomicU32>,
+        metrics: Arc<Metrics>,
+        waiting_room: bool,
+    ) -> Self {
         Self {
             id,
             next_peer_id,
+            metrics,
             peers: Vec::new(),
+            metadata: RoomMetadata::default(),
+            created: SystemTime::now(),
+            creator: None,
+            offers_scheduled: false,
+            passcode: None,
+            waiting_room,
+            lobby: Vec::new(),
+            locked: false,
+            breakout: None,
+            breakouts: None,
         }
     }
 
@@ -37,6 +154,19 @@ impl Room {
         self.try_get_peer_index(id).unwrap()
     }
 
+    pub fn has_peer(&self, id: u32) -> bool {
+        self.try_get_peer_index(id).is_some()
+    }
+
+    pub fn try_get_peer(&self, id: u32) -> Option<&Peer> {
+        Some(&self.peers[self.try_get_peer_index(id)?])
+    }
+
+    pub fn try_get_peer_mut(&mut self, id: u32) -> Option<&mut Peer> {
+        let index = self.try_get_peer_index(id)?;
+        Some(&mut self.peers[index])
+    }
+
     pub fn get_peer(&self, id: u32) -> &Peer {
         let index = self.get_peer_index(id);
         &self.peers[index]
@@ -47,9 +177,30 @@ impl Room {
         &mut self.peers[index]
     }
 
-    pub async fn add_peer(&mut self, signal_tx: signal::Sender) -> Result<u32> {
+    /// Whether anyone is in the meeting, counting the peers out in its breakout rooms.
+    pub fn is_occupied(&self) -> bool {
+        !self.peers.is_empty() || self.breakouts.is_some()
+    }
+
+    pub fn occupancy(&self) -> Occupancy {
+        let publishers = self.peers.iter().filter(|p| p.mode.publishes()).count();
+        Occupancy {
+            publishers,
+            viewers: self.peers.len() - publishers,
+        }
+    }
+
+    pub async fn add_peer(
+        &mut self,
+        mode: Mode,
+        role: Role,
+        profile: Profile,
+        signal_tx: signal::Sender,
+        error_tx: error::Sender,
+    ) -> Result<u32> {
         let id = self.next_peer_id.fetch_add(1, Ordering::Relaxed);
-        self.peers.push(Peer::new(id, self.id, signal_tx).await?);
+        self.peers
+            .push(Peer::new(id, self.id, mode, role, profile, signal_tx, error_tx).await?);
         Ok(id)
     }
 
@@ -57,13 +208,14 @@ impl Room {
         Some(self.peers.swap_remove(self.try_get_peer_index(id)?))
     }
 
+    /// Adds the tracks of the other peers that the peer accepts and isn't sent yet.
     pub async fn add_other_peers_tracks(&self, peer: &Peer) -> Result<()> {
         for other in &self.peers {
-            if other.id == peer.id {
+            if other.id == peer.id || !peer.mode.receives_from(other.id) {
                 continue;
             }
-            for track in [&other.video, &other.audio] {
-                if let Some(track) = track {
+            for track in other.tracks() {
+                if other.forwards(track) && peer.accepts_track(&track.inner).await {
                     peer.add_sendonly_transceiver(&track.inner).await?;
                 }
             }
@@ -77,61 +229,362 @@ impl Room {
             .filter(|p| {
                 p.conn.connection_state() == RTCPeerConnectionState::Connected
                     && p.id != for_peer_id
+                    && p.mode.publishes()
             })
-            .map(|p| ServerMessagePeer {
-                id: p.id,
-                name: p.name.clone().unwrap(),
-            })
+            .map(Peer::info)
             .collect()
     }
 
-    pub async fn send_joined_peer(&mut self, id: u32, name: String) -> Result<()> {
+    pub async fn send_joined_peer(&mut self, info: ServerMessagePeer) -> Result<()> {
         for other in &mut self.peers {
-            if other.id == id {
+            if other.id == info.id {
                 continue;
             }
             other
-                .send_message(ServerMessage::PeerJoined(ServerMessagePeer {
-                    id,
-                    name: name.clone(),
-                }))
+                .send_message(ServerMessage::PeerJoined(info.clone()))
                 .await?;
         }
         Ok(())
     }
 
-    pub async fn handle_peer_leave(&mut self, id: u32) -> Result<bool> {
-        Ok(if let Some(peer) = self.remove_peer(id) {
-            peer.close().await?;
-            for other in &mut self.peers {
-                other.send_message(ServerMessage::PeerLeft(id)).await?;
-                other.stop_transceivers(id).await?;
-                other.send_offer().await?;
+    pub async fn handle_peer_leave(&mut self, id: u32) -> Result<()> {
+        let Some((peer, detached)) = self.detach_peer(id).await else {
+            return Ok(());
+        };
+        let closed = peer.close().await;
+        detached.and(closed)
+    }
+
+    /// Takes the peer out of the room and stops forwarding its tracks to the others.
+    /// The peer is handed back even if updating the others fails, along with the first
+    /// error, so that it can still be closed or moved.
+    async fn detach_peer(&mut self, id: u32) -> Option<(Peer, Result<()>)> {
+        let peer = self.remove_peer(id)?;
+        let mut result = Ok(());
+        for other in &mut self.peers {
+            result = result.and(other.send_message(ServerMessage::PeerLeft(id)).await);
+            result = result.and(other.stop_transceivers(id, None).await);
+            other.request_offer();
+        }
+        // The departed peer's video may have freed decodes for tracks left out before.
+        result = result.and(self.add_missing_tracks().await);
+        if peer.role == Role::Host
+            && let Some(host) = self.next_host()
+        {
+            result = result.and(self.set_role(host, Role::Host).await);
+        }
+        Some((peer, result))
+    }
+
+    /// Takes the peer out of the room to move it to another, keeping its connection
+    /// but no longer sending it the tracks of this room. Like [`Self::detach_peer`], it
+    /// hands the peer back whether or not that all worked.
+    pub async fn move_peer_out(&mut self, id: u32) -> Option<(Peer, Result<()>)> {
+        let (mut peer, mut result) = self.detach_peer(id).await?;
+        for other in &self.peers {
+            result = result.and(peer.stop_transceivers(other.id, None).await);
+        }
+        peer.request_offer();
+        Some((peer, result))
+    }
+
+    /// Puts a peer moved out of another room into this one, as if it had just connected.
+    pub async fn move_peer_in(&mut self, mut peer: Peer) -> Result<()> {
+        let id = peer.id;
+        let info = peer.info();
+        peer.room_id = self.id;
+        self.peers.push(peer);
+        let breakout = self.breakout;
+        let room_info = self.info();
+        let peers = self.get_server_message_peers(id);
+        let peer = self.get_peer_mut(id);
+        peer.send_message(ServerMessage::Moved(breakout)).await?;
+        peer.send_message(ServerMessage::Room(room_info)).await?;
+        peer.send_message(ServerMessage::Peers(peers)).await?;
+        self.send_joined_peer(info).await?;
+        for peer in &mut self.peers {
+            peer.request_offer();
+        }
+        self.add_missing_tracks().await
+    }
+
+    /// Adds every renegotiating peer the tracks it accepts but isn't sent yet.
+    async fn add_missing_tracks(&self) -> Result<()> {
+        for peer in &self.peers {
+            if peer.mode.renegotiates() {
+                self.add_other_peers_tracks(peer).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The `/signal` peer that takes over from a host who left: the earliest co-host,
+    /// or the earliest participant if there is none.
+    fn next_host(&self) -> Option<u32> {
+        [Role::CoHost, Role::Participant]
+            .into_iter()
+            .find_map(|role| {
+                self.peers
+                    .iter()
+                    .filter(|p| p.mode == Mode::Signal && p.role == role)
+                    .map(|p| p.id)
+                    .min()
+            })
+    }
+
+    pub fn has_host(&self) -> bool {
+        self.peers.iter().any(|p| p.role == Role::Host)
+    }
+
+    /// The ids of the peer's tracks that are forwarded to others.
+    fn forwarded_tracks(&self, id: u32) -> Vec<String> {
+        let peer = self.get_peer(id);
+        peer.tracks()
+            .filter(|track| peer.forwards(track))
+            .map(|track| track.inner.id().to_string())
+            .collect()
+    }
+
+    /// Stops or starts forwarding the peer's tracks after what is forwarded of them
+    /// changed from `before`.
+    async fn reforward(&mut self, id: u32, before: Vec<String>) -> Result<()> {
+        let after = self.forwarded_tracks(id);
+        if after == before {
+            return Ok(());
+        }
+        for other in &mut self.peers {
+            if other.id == id {
+                continue;
+            }
+            for track_id in before.iter().filter(|track_id| !after.contains(track_id)) {
+                other.stop_transceivers(id, Some(track_id)).await?;
+            }
+            other.request_offer();
+        }
+        self.add_missing_tracks().await
+    }
+
+    /// Sends the message to the peer and to everyone who can see it.
+    async fn send_about_peer(&mut self, id: u32, message: ServerMessage) -> Result<()> {
+        let visible = self.get_peer(id).mode.publishes();
+        for other in &mut self.peers {
+            if other.id == id || visible {
+                other.send_message(message.clone()).await?;
             }
-            true
+        }
+        Ok(())
+    }
+
+    /// Changes a peer's role, forwarding its tracks to others only as far as the new
+    /// permissions allow, and tells the peer and everyone who can see it.
+    pub async fn set_role(&mut self, id: u32, role: Role) -> Result<()> {
+        let before = self.forwarded_tracks(id);
+        let peer = self.get_peer_mut(id);
+        let moderated = peer.role.permissions().moderate;
+        peer.role = role;
+        let info = peer.info();
+        self.send_about_peer(id, ServerMessage::PeerUpdated(info))
+            .await?;
+        if !moderated && role.permissions().moderate {
+            self.send_lobby(id).await?;
+        }
+        self.reforward(id, before).await
+    }
+
+    /// Starts or stops forwarding one of a peer's tracks for a moderator.
+    pub async fn set_muted(&mut self, mute: ServerMessageMute, muted: bool) -> Result<()> {
+        let before = self.forwarded_tracks(mute.peer);
+        let peer = self.get_peer_mut(mute.peer);
+        let changed = if muted {
+            peer.muted.insert(mute.kind)
+        } else {
+            peer.muted.remove(&mute.kind)
+        };
+        if !changed {
+            return Ok(());
+        }
+        let message = if muted {
+            ServerMessage::Muted(mute)
         } else {
-            false
-        })
+            ServerMessage::Unmuted(mute)
+        };
+        self.send_about_peer(mute.peer, message).await?;
+        self.reforward(mute.peer, before).await
+    }
+
+    pub async fn broadcast(&mut self, message: ServerMessage) -> Result<()> {
+        for peer in &mut self.peers {
+            peer.send_message(message.clone()).await?;
+        }
+        Ok(())
+    }
+
+    /// Whether the join has to wait in the lobby. Nobody waits to open a room.
+    pub fn must_wait(&self, admission: &Admission, config: &Config) -> bool {
+        let trusted = config.admit_authenticated && admission.profile.authenticated;
+        self.waiting_room && !admission.admitted && self.is_occupied() && !trusted
+    }
+
+    async fn send_to_moderators(&mut self, message: ServerMessage) -> Result<()> {
+        for peer in &mut self.peers {
+            if peer.role.permissions().moderate {
+                peer.send_message(message.clone()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Puts a join in the lobby and returns its id and the moderators' decision to come.
+    pub async fn knock(&mut self, name: String) -> Result<(u32, oneshot::Receiver<bool>)> {
+        let (decision, decision_rx) = oneshot::channel();
+        let knock = Knock {
+            id: self.next_peer_id.fetch_add(1, Ordering::Relaxed),
+            name,
+            since: SystemTime::now(),
+            decision,
+        };
+        let (id, info) = (knock.id, knock.info());
+        self.lobby.push(knock);
+        self.send_to_moderators(ServerMessage::LobbyJoined(info))
+            .await?;
+        Ok((id, decision_rx))
+    }
+
+    fn take_knock(&mut self, id: u32) -> Option<Knock> {
+        let index = self.lobby.iter().position(|knock| knock.id == id)?;
+        Some(self.lobby.remove(index))
+    }
+
+    /// Admits or denies a join from the lobby.
+    pub async fn answer_knock(&mut self, id: u32, admit: bool) -> Result<()> {
+        let knock = self.take_knock(id).ok_or(Error::UnknownPeer(id))?;
+        knock.decision.send(admit).ok();
+        self.send_to_moderators(ServerMessage::LobbyLeft(id)).await
+    }
+
+    /// Takes a join that went away out of the lobby.
+    pub async fn withdraw_knock(&mut self, id: u32) -> Result<()> {
+        if self.take_knock(id).is_some() {
+            self.send_to_moderators(ServerMessage::LobbyLeft(id))
+                .await?;
+        }
+        Ok(())
+    }
+
+    pub async fn set_waiting_room(&mut self, on: bool) -> Result<()> {
+        self.waiting_room = on;
+        for peer in &mut self.peers {
+            peer.send_message(ServerMessage::WaitingRoom(on)).await?;
+        }
+        if !on {
+            for id in self.lobby.iter().map(|knock| knock.id).collect::<Vec<_>>() {
+                self.answer_knock(id, true).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Locking the room also turns away everyone waiting in the lobby.
+    pub async fn set_locked(&mut self, locked: bool) -> Result<()> {
+        self.locked = locked;
+        for peer in &mut self.peers {
+            peer.send_message(ServerMessage::Locked(locked)).await?;
+        }
+        if locked {
+            for id in self.lobby.iter().map(|knock| knock.id).collect::<Vec<_>>() {
+                self.answer_knock(id, false).await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn info(&self) -> ServerMessageRoom {
+        let created = self.created.duration_since(UNIX_EPOCH).unwrap_or_default();
+        ServerMessageRoom {
+            metadata: self.metadata.clone(),
+            created: created.as_millis() as u64,
+            creator: self.creator.clone(),
+        }
+    }
+
+    pub async fn set_metadata(&mut self, metadata: RoomMetadata) -> Result<()> {
+        self.metadata = metadata;
+        self.broadcast(ServerMessage::Room(self.info())).await
+    }
+
+    /// Brings a peer that just became a moderator up to date on the lobby.
+    pub async fn send_lobby(&mut self, id: u32) -> Result<()> {
+        let waiting_room = self.waiting_room;
+        let knocks: Vec<_> = self.lobby.iter().map(Knock::info).collect();
+        let peer = self.get_peer_mut(id);
+        peer.send_message(ServerMessage::WaitingRoom(waiting_room))
+            .await?;
+        for knock in knocks {
+            peer.send_message(ServerMessage::LobbyJoined(knock)).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn send_chat(&mut self, from: u32, text: String) -> Result<()> {
+        for other in &mut self.peers {
+            if other.id == from {
+                continue;
+            }
+            other
+                .send_message(ServerMessage::Chat(ServerMessageChat {
+                    from,
+                    text: text.clone(),
+                }))
+                .await?;
+        }
+        Ok(())
     }
 
     pub async fn add_peer_track_to_others(
         &mut self,
         peer_id: u32,
         track: Arc<TrackLocalStaticRTP>,
-        send_offer: bool,
+        request_offer: bool,
     ) -> Result<()> {
         for other in &mut self.peers {
-            if other.id == peer_id {
+            if other.id == peer_id
+                || !other.mode.receives_from(peer_id)
+                || !other.mode.renegotiates()
+                || !other.accepts_track(&track).await
+            {
                 continue;
             }
             other.add_sendonly_transceiver(&track).await?;
-            if send_offer {
-                other.send_offer().await?;
+            if request_offer {
+                other.request_offer();
             }
         }
         Ok(())
     }
 
+    pub fn has_requested_offers(&self) -> bool {
+        self.peers.iter().any(|p| p.negotiation.requested > 0)
+    }
+
+    /// Sends a single offer to every peer that requested renegotiation since the last call.
+    /// A failed offer doesn't hold up the others; it is returned with the error sender
+    /// of its peer, to be reported once the room is unlocked.
+    pub async fn send_offers(&mut self) -> Vec<(error::Sender, Error)> {
+        self.offers_scheduled = false;
+        let mut failures = Vec::new();
+        for peer in &mut self.peers {
+            if peer.negotiation.requested == 0 {
+                continue;
+            }
+            self.metrics.record_offer(peer.negotiation.requested);
+            peer.negotiation.requested = 0;
+            if let Err(e) = peer.send_offer().await {
+                failures.push((peer.error_tx.clone(), e));
+            }
+        }
+        failures
+    }
+
     pub async fn send_pli(&self, peer_id: u32) -> Result<()> {
         for peer in &self.peers {
             if peer.id == peer_id {
@@ -141,3 +594,51 @@ impl Room {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::profile;
+
+    #[tokio::test]
+    async fn failed_offer_is_reported_to_its_own_peer() {
+        let config = Config::default();
+        let mut room = Room::new(1, Arc::default(), Arc::default(), false);
+        let (error_tx1, mut error_rx1) = error::channel();
+        let (error_tx2, mut error_rx2) = error::channel();
+        let id1 = room
+            .add_peer(
+                Mode::Signal,
+                Role::Participant,
+                profile("one"),
+                signal::Sender::new(&config),
+                error_tx1,
+            )
+            .await
+            .unwrap();
+        let id2 = room
+            .add_peer(
+                Mode::Signal,
+                Role::Participant,
+                profile("two"),
+                signal::Sender::new(&config),
+                error_tx2,
+            )
+            .await
+            .unwrap();
+        // An ICE restart fails before the first offer has gathered anything.
+        room.get_peer_mut(id1).negotiation.ice_restart = true;
+        room.get_peer_mut(id1).request_offer();
+        room.get_peer_mut(id2).request_offer();
+
+        let failures = room.send_offers().await;
+        assert_eq!(failures.len(), 1);
+        for (error_tx, e) in failures {
+            error_tx.report(e).await;
+        }
+        assert!(error_rx1.recv().await.is_some());
+        let none = tokio::time::timeout(std::time::Duration::ZERO, error_rx2.recv()).await;
+        assert!(none.is_err());
+        assert!(room.get_peer(id2).negotiation.offer_id.is_some());
+    }
+}
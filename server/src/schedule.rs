@@ -0,0 +1,331 @@
+//! Meetings scheduled ahead of time. Each one reserves a room code until it ends, and
+//! its room can't be opened until shortly before it starts.
+
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    Json,
+    extract::{self, Path},
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{Error, Result, check_limit},
+    server::authorize,
+    state::State,
+};
+
+/// Longest host or invitee address, in bytes, as SMTP allows.
+const MAX_ADDRESS_LEN: usize = 254;
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ScheduleMeeting {
+    pub title: String,
+    /// In milliseconds since the Unix epoch, like `ends`.
+    pub starts: u64,
+    pub ends: u64,
+    /// Email address of the host, the invitation's organizer. It gives nobody the host
+    /// role, which goes to the room's first `/signal` peer as usual.
+    pub host: String,
+    /// Email addresses of the invitees.
+    #[serde(default)]
+    pub invitees: Vec<String>,
+    /// Passcode the meeting's room takes. Not kept, nor shown with the meeting.
+    #[serde(default)]
+    pub passcode: Option<String>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Meeting {
+    pub code: String,
+    pub title: String,
+    pub starts: u64,
+    pub ends: u64,
+    pub host: String,
+    pub invitees: Vec<String>,
+    /// Where the web client joins the meeting.
+    pub link: String,
+}
+
+impl Meeting {
+    /// When the meeting's room can first be opened, `early_join` before the start.
+    pub fn opens(&self, early_join: Duration) -> SystemTime {
+        let starts = UNIX_EPOCH + Duration::from_millis(self.starts);
+        starts.checked_sub(early_join).unwrap_or(UNIX_EPOCH)
+    }
+
+    pub fn ends(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.ends)
+    }
+
+    /// The meeting as an iCalendar event with the join link.
+    pub fn to_ics(&self) -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut lines = vec![
+            "BEGIN:VCALENDAR".to_string(),
+            "VERSION:2.0".into(),
+            "PRODID:-//zoom//scheduled meetings//EN".into(),
+            "METHOD:REQUEST".into(),
+            "BEGIN:VEVENT".into(),
+            format!("UID:{}@zoom", self.code),
+            format!("DTSTAMP:{}", ics_time(now.as_millis() as u64)),
+            format!("DTSTART:{}", ics_time(self.starts)),
+            format!("DTEND:{}", ics_time(self.ends)),
+            format!("SUMMARY:{}", ics_text(&self.title)),
+            format!(
+                "DESCRIPTION:{}",
+                ics_text(&format!("Join at {}", self.link))
+            ),
+            format!("LOCATION:{}", ics_text(&self.link)),
+            format!("URL:{}", self.link),
+            format!("ORGANIZER:mailto:{}", self.host),
+        ];
+        for invitee in &self.invitees {
+            lines.push(format!("ATTENDEE;RSVP=TRUE:mailto:{}", invitee));
+        }
+        lines.push("END:VEVENT".into());
+        lines.push("END:VCALENDAR".into());
+        let mut ics = String::new();
+        for line in lines {
+            fold(&line, &mut ics);
+        }
+        ics
+    }
+}
+
+/// Formats a Unix time in milliseconds as an iCalendar UTC date-time, like
+/// `20250102T030405Z`.
+fn ics_time(millis: u64) -> String {
+    let seconds = millis / 1000;
+    let (year, month, day) = civil_from_days((seconds / 86400) as i64);
+    let time = seconds % 86400;
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+/// The Gregorian date of a day counted from 1970-01-01, after Howard Hinnant's
+/// `civil_from_days`.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month as u32, day as u32)
+}
+
+fn ics_text(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+/// Appends a content line, folded into lines of at most 75 bytes.
+fn fold(line: &str, ics: &mut String) {
+    let mut len = 0;
+    for c in line.chars() {
+        if len + c.len_utf8() > 75 {
+            ics.push_str("\r\n ");
+            len = 1;
+        }
+        ics.push(c);
+        len += c.len_utf8();
+    }
+    ics.push_str("\r\n");
+}
+
+fn check_address(address: &str) -> Result<()> {
+    check_limit("address", address.len(), MAX_ADDRESS_LEN)?;
+    let valid = address
+        .split_once('@')
+        .is_some_and(|(local, domain)| !local.is_empty() && !domain.is_empty())
+        && !address.chars().any(|c| {
+            c.is_whitespace() || c.is_control() || matches!(c, ':' | ';' | ',' | '"' | '<' | '>')
+        });
+    if !valid {
+        return Err(Error::Meeting("invalid address"));
+    }
+    Ok(())
+}
+
+/// `POST /meetings`: schedules a meeting and responds with it, its code and join link
+/// included.
+pub async fn post_handler(
+    extract::State(state): extract::State<Arc<State>>,
+    headers: HeaderMap,
+    Json(request): Json<ScheduleMeeting>,
+) -> Response {
+    match schedule(&state, &headers, request).await {
+        Ok(meeting) => (
+            StatusCode::CREATED,
+            [(header::LOCATION, format!("/meetings/{}", meeting.code))],
+            Json(meeting),
+        )
+            .into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+async fn schedule(state: &State, headers: &HeaderMap, request: ScheduleMeeting) -> Result<Meeting> {
+    authorize(state, headers)?;
+    let limits = &state.config.limits;
+    check_limit("title", request.title.chars().count(), limits.max_title_len)?;
+    if request.title.trim().is_empty() || request.title.chars().any(char::is_control) {
+        return Err(Error::Meeting("invalid title"));
+    }
+    check_address(&request.host)?;
+    check_limit("invitees", request.invitees.len(), limits.max_invitees)?;
+    for invitee in &request.invitees {
+        check_address(invitee)?;
+    }
+    if request.ends <= request.starts {
+        return Err(Error::Meeting("ends before it starts"));
+    }
+    let ends = UNIX_EPOCH + Duration::from_millis(request.ends);
+    let Ok(left) = ends.duration_since(SystemTime::now()) else {
+        return Err(Error::Meeting("already over"));
+    };
+    let code = state.reserve_room(left, request.passcode).await?;
+    let link = format!(
+        "{}/?code={}",
+        state.config.web_url.trim_end_matches('/'),
+        code
+    );
+    let meeting = Meeting {
+        code,
+        title: request.title,
+        starts: request.starts,
+        ends: request.ends,
+        host: request.host,
+        invitees: request.invitees,
+        link,
+    };
+    state.add_meeting(meeting.clone());
+    println!("[{}] meeting scheduled", meeting.code);
+    Ok(meeting)
+}
+
+/// `GET /meetings/{code}`
+pub async fn get_handler(
+    extract::State(state): extract::State<Arc<State>>,
+    Path(code): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, &headers) {
+        return e.into_response();
+    }
+    match state.get_meeting(&code) {
+        Some(meeting) => Json(meeting).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// `GET /meetings/{code}/meeting.ics`: the meeting for calendars.
+pub async fn ics_handler(
+    extract::State(state): extract::State<Arc<State>>,
+    Path(code): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, &headers) {
+        return e.into_response();
+    }
+    let Some(meeting) = state.get_meeting(&code) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    (
+        [
+            (
+                header::CONTENT_TYPE,
+                "text/calendar; charset=utf-8".to_string(),
+            ),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}.ics\"", code),
+            ),
+        ],
+        meeting.to_ics(),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meeting(title: &str) -> Meeting {
+        Meeting {
+            code: "abc-def-ghi".into(),
+            title: title.into(),
+            starts: 1735787045000,
+            ends: 1735790645000,
+            host: "host@example.com".into(),
+            invitees: vec!["a@example.com".into(), "b@example.com".into()],
+            link: "https://example.com/?code=abc-def-ghi".into(),
+        }
+    }
+
+    #[test]
+    fn ics_times_are_utc() {
+        assert_eq!(ics_time(0), "19700101T000000Z");
+        assert_eq!(ics_time(1735787045000), "20250102T030405Z");
+        assert_eq!(ics_time(1709208000999), "20240229T120000Z");
+        assert_eq!(ics_time(951868800000), "20000301T000000Z");
+    }
+
+    #[test]
+    fn ics_describes_the_event() {
+        let ics = meeting("Weekly sync; notes, too").to_ics();
+        assert!(ics.ends_with("END:VEVENT\r\nEND:VCALENDAR\r\n"));
+        let lines: Vec<_> = ics.split("\r\n").collect();
+        assert_eq!(lines[0], "BEGIN:VCALENDAR");
+        for line in [
+            "UID:abc-def-ghi@zoom",
+            "DTSTART:20250102T030405Z",
+            "DTEND:20250102T040405Z",
+            r"SUMMARY:Weekly sync\; notes\, too",
+            "URL:https://example.com/?code=abc-def-ghi",
+            "ORGANIZER:mailto:host@example.com",
+            "ATTENDEE;RSVP=TRUE:mailto:a@example.com",
+            "ATTENDEE;RSVP=TRUE:mailto:b@example.com",
+        ] {
+            assert!(lines.contains(&line), "missing {}", line);
+        }
+    }
+
+    #[test]
+    fn long_ics_lines_are_folded() {
+        let title = "é".repeat(100);
+        let ics = meeting(&title).to_ics();
+        for line in ics.split("\r\n") {
+            assert!(line.len() <= 75, "{} bytes: {}", line.len(), line);
+        }
+        let unfolded = ics.replace("\r\n ", "");
+        assert!(unfolded.contains(&format!("SUMMARY:{}\r\n", title)));
+    }
+}
@@ -0,0 +1,152 @@
+use jsonschema::Validator;
+use once_cell::sync::Lazy;
+use schemars::{Schema, schema_for};
+use serde_json::{Value, json};
+use ts_rs::TS;
+
+use crate::{
+    error::{Error, Result},
+    signal::{
+        self, AssignBreakout, Capabilities, ErrorCode, Handshake, Join, MediaKind, Mute,
+        OpenBreakouts, PeerEnvelope, PeerMessage, Permissions, Role, RoomMetadata, ServerEnvelope,
+        ServerMessage, ServerMessageBreakouts, ServerMessageChat, ServerMessageError,
+        ServerMessageId, ServerMessageKnock, ServerMessageMute, ServerMessagePeer,
+        ServerMessageRoom, SetRole,
+    },
+};
+
+static PEER_ENVELOPE_VALIDATOR: Lazy<Validator> =
+    Lazy::new(|| jsonschema::validator_for(&schema_for!(PeerEnvelope).to_value()).unwrap());
+
+/// JSON Schemas of the messages a client can exchange with the server, by file name.
+pub fn json_schemas() -> Vec<(&'static str, Schema)> {
+    vec![
+        ("handshake", schema_for!(Handshake)),
+        ("peer-envelope", schema_for!(PeerEnvelope)),
+        ("server-envelope", schema_for!(ServerEnvelope)),
+    ]
+}
+
+/// TypeScript definitions of the signaling protocol, for `web/src/protocol/signal.d.ts`.
+pub fn typescript() -> String {
+    let cfg = ts_rs::Config::new();
+    let decls = [
+        Handshake::decl(&cfg),
+        ServerMessageId::decl(&cfg),
+        Role::decl(&cfg),
+        Permissions::decl(&cfg),
+        ServerMessagePeer::decl(&cfg),
+        ServerMessageChat::decl(&cfg),
+        MediaKind::decl(&cfg),
+        ServerMessageMute::decl(&cfg),
+        ServerMessageKnock::decl(&cfg),
+        ServerMessageBreakouts::decl(&cfg),
+        RoomMetadata::decl(&cfg),
+        ServerMessageRoom::decl(&cfg),
+        ErrorCode::decl(&cfg),
+        ServerMessageError::decl(&cfg),
+        ServerMessage::decl(&cfg),
+        ServerEnvelope::decl(&cfg),
+        Capabilities::decl(&cfg),
+        Join::decl(&cfg),
+        SetRole::decl(&cfg),
+        Mute::decl(&cfg),
+        OpenBreakouts::decl(&cfg),
+        AssignBreakout::decl(&cfg),
+        PeerMessage::decl(&cfg),
+        PeerEnvelope::decl(&cfg),
+    ];
+    let mut ts = String::from(
+        "// Generated by `cargo run --bin schema` from server/src/signal.rs, do not edit.\n",
+    );
+    for decl in decls {
+        ts.push_str("\nexport ");
+        ts.push_str(&decl);
+        ts.push('\n');
+    }
+    ts
+}
+
+/// The protocol's constants, for `web/src/protocol/constants.ts`.
+pub fn typescript_constants() -> String {
+    let constants = [
+        ("PROTOCOL_VERSION", json!(signal::PROTOCOL_VERSION)),
+        (
+            "CLOSE_UNSUPPORTED_VERSION",
+            json!(signal::CLOSE_UNSUPPORTED_VERSION),
+        ),
+        ("CLOSE_RESUME_FAILED", json!(signal::CLOSE_RESUME_FAILED)),
+        (
+            "CLOSE_CONNECTION_TIMEOUT",
+            json!(signal::CLOSE_CONNECTION_TIMEOUT),
+        ),
+        ("CLOSE_JOIN_TIMEOUT", json!(signal::CLOSE_JOIN_TIMEOUT)),
+        ("CLOSE_JOIN_REJECTED", json!(signal::CLOSE_JOIN_REJECTED)),
+        ("CLOSE_KICKED", json!(signal::CLOSE_KICKED)),
+        ("CLOSE_MEETING_ENDED", json!(signal::CLOSE_MEETING_ENDED)),
+        ("CLOSE_DENIED", json!(signal::CLOSE_DENIED)),
+        (
+            "FEATURE_END_OF_CANDIDATES",
+            json!(signal::FEATURE_END_OF_CANDIDATES),
+        ),
+    ];
+    let mut ts = String::from(
+        "// Generated by `cargo run --bin schema` from server/src/signal.rs, do not edit.\n\n",
+    );
+    for (name, value) in constants {
+        ts.push_str(&format!("export const {} = {};\n", name, value));
+    }
+    ts
+}
+
+/// Checks a decoded client message against its schema before it is deserialized,
+/// so that clients learn the path of the offending value rather than a serde error.
+pub fn validate_peer_envelope(value: &Value) -> Result<()> {
+    match PEER_ENVELOPE_VALIDATOR.validate(value) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(Error::Schema {
+            path: e.instance_path().to_string(),
+            message: e.to_string(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use serde_json::json;
+
+    use super::*;
+
+    /// The generated files are checked in, so they have to be regenerated with
+    /// `cargo run --bin schema` whenever the protocol changes.
+    #[test]
+    fn generated_files_are_up_to_date() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../web/src/protocol");
+        let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+        for (name, schema) in json_schemas() {
+            let json = serde_json::to_string_pretty(&schema).unwrap() + "\n";
+            let file = format!("{}.schema.json", name);
+            assert!(read(&file) == json, "{} is out of date", file);
+        }
+        assert!(
+            read("signal.d.ts") == typescript(),
+            "signal.d.ts is out of date"
+        );
+        assert!(
+            read("constants.ts") == typescript_constants(),
+            "constants.ts is out of date"
+        );
+    }
+
+    #[test]
+    fn invalid_envelopes_are_rejected_with_their_path() {
+        validate_peer_envelope(&json!({"id": 1, "message": {"chat": "hi"}})).unwrap();
+        let result = validate_peer_envelope(&json!({"id": "one", "message": {"chat": "hi"}}));
+        let Err(Error::Schema { path, .. }) = result else {
+            panic!("expected a schema error, got {:?}", result);
+        };
+        assert_eq!(path, "/id");
+    }
+}
@@ -1,23 +1,37 @@
 use crate::{
-    code, error,
+    code,
+    config::Config,
+    error::{self, Error},
+    peer::{Admission, Mode},
+    room::{Occupancy, Room},
+    schedule,
     session::{Session, SessionHdlrFns},
-    signal::{self, ServerMessage},
+    signal::{self, Connection, PeerMessage, Receiver as _, ServerMessage},
+    sse,
     state::State,
-    track,
+    track, whep, whip,
 };
 use axum::{
     Json, Router,
     extract::{
-        self, Query,
-        ws::{WebSocket, WebSocketUpgrade},
+        self, ConnectInfo, Path, Query,
+        ws::{WebSocket, WebSocketUpgrade, close_code},
     },
-    http::StatusCode,
-    response::IntoResponse,
-    routing::get,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, patch, post},
 };
 use serde_json::json;
-use std::{collections::HashMap, sync::Arc};
-use tokio::net::TcpListener;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+use tokio::{
+    net::TcpListener,
+    sync::{Mutex, oneshot},
+};
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
 
 pub struct Server {
     router: Router,
@@ -25,11 +39,32 @@ pub struct Server {
 }
 
 impl Server {
-    pub fn new(addr: &str) -> Self {
+    pub fn new(addr: &str, config: Config) -> Self {
         let router = Router::new()
             .route("/code", get(code_handler))
             .route("/signal", get(signal_handler))
-            .with_state(Arc::new(State::new()));
+            .route("/signal/sse", get(sse::events_handler))
+            .route(
+                "/signal/sse/{connection}",
+                post(sse::post_handler).delete(sse::delete_handler),
+            )
+            .route("/metrics", get(metrics_handler))
+            .route("/rooms/{code}", get(room_handler))
+            .route("/rooms/{code}/occupancy", get(occupancy_handler))
+            .route("/meetings", post(schedule::post_handler))
+            .route("/meetings/{code}", get(schedule::get_handler))
+            .route("/meetings/{code}/meeting.ics", get(schedule::ics_handler))
+            .route("/whip/{code}", post(whip::post_handler))
+            .route(
+                "/whip/{code}/{token}",
+                patch(whip::patch_handler).delete(whip::delete_handler),
+            )
+            .route("/whep/{code}", post(whep::post_handler))
+            .route(
+                "/whep/{code}/{token}",
+                patch(whep::patch_handler).delete(whep::delete_handler),
+            )
+            .with_state(Arc::new(State::new(config)));
         Self {
             router,
             addr: addr.into(),
@@ -37,59 +72,330 @@ impl Server {
     }
 
     pub async fn run(self) {
-        let listener = TcpListener::bind(self.addr).await.unwrap();
-        axum::serve(listener, self.router).await.unwrap();
+        let listener = TcpListener::bind(&self.addr).await.unwrap();
+        self.serve(listener).await;
+    }
+
+    /// Serves on a listener that is already bound, ignoring the address.
+    pub async fn serve(self, listener: TcpListener) {
+        let service = self
+            .router
+            .into_make_service_with_connect_info::<SocketAddr>();
+        axum::serve(listener, service).await.unwrap();
+    }
+}
+
+/// Requests besides joins, like reserving codes and reading meetings or rooms, take an
+/// auth token whenever joining does.
+pub fn authorize(state: &State, headers: &HeaderMap) -> error::Result<()> {
+    match whip::bearer_token(headers) {
+        Some(token) if state.config.auth_tokens.contains(&token) => Ok(()),
+        Some(_) => Err(Error::Unauthorized("invalid auth token")),
+        None if state.config.require_auth => Err(Error::Unauthorized("authentication required")),
+        None => Ok(()),
+    }
+}
+
+/// `GET /code`: a code reserved for a new room, which takes the passcode in the
+/// `X-Room-Passcode` header if there is one.
+async fn code_handler(
+    extract::State(state): extract::State<Arc<State>>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, &headers) {
+        return e.into_response();
+    }
+    let ttl = state.config.reservation_ttl;
+    match state.reserve_room(ttl, whip::passcode(&headers)).await {
+        Ok(code) => Json(json!({"code": code})).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+async fn metrics_handler(extract::State(state): extract::State<Arc<State>>) -> impl IntoResponse {
+    Json(state.metrics.snapshot(state.occupancy()))
+}
+
+/// Finds a room for a REST read, which takes what a join would: an auth token whenever
+/// joining does, and the room's passcode if it has one. Wrong passcodes count against
+/// the client like a join's.
+async fn find_room(
+    state: &State,
+    code: &str,
+    headers: &HeaderMap,
+    client: IpAddr,
+) -> error::Result<Option<Arc<Mutex<Room>>>> {
+    authorize(state, headers)?;
+    let Some(room) = state.find_room(code) else {
+        return Ok(None);
+    };
+    let passcode = room.lock().await.passcode.clone();
+    if let Some(passcode) = passcode {
+        let attempt = whip::passcode(headers);
+        state
+            .check_passcode(passcode, attempt.as_deref(), client)
+            .await?;
+    }
+    Ok(Some(room))
+}
+
+/// `GET /rooms/{code}/occupancy`: the room's participants, zero for rooms nobody is in.
+async fn occupancy_handler(
+    extract::State(state): extract::State<Arc<State>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(code): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let occupancy = match find_room(&state, &code, &headers, addr.ip()).await {
+        Ok(Some(room)) => room.lock().await.occupancy(),
+        Ok(None) => Occupancy::default(),
+        Err(e) => return e.into_response(),
+    };
+    Json(occupancy).into_response()
+}
+
+/// `GET /rooms/{code}`: the metadata of an open room.
+async fn room_handler(
+    extract::State(state): extract::State<Arc<State>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(code): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    match find_room(&state, &code, &headers, addr.ip()).await {
+        Ok(Some(room)) => Json(room.lock().await.info()).into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => e.into_response(),
     }
 }
 
-async fn code_handler() -> impl IntoResponse {
-    Json(json!({"code": code::generate()}))
+pub fn bad_request(message: &str) -> Response {
+    (StatusCode::BAD_REQUEST, Json(json!({"error": message}))).into_response()
+}
+
+/// Query parameters shared by every signaling transport.
+pub struct SignalParams {
+    pub code: String,
+    pub resume: Option<String>,
+    pub last: u32,
+}
+
+impl SignalParams {
+    pub fn parse(params: &HashMap<String, String>) -> Result<Self, &'static str> {
+        let code = params.get("code").ok_or("missing query parameter 'code'")?;
+        if !code::is_valid(code) {
+            return Err("invalid query parameter 'code'");
+        }
+        let last = match params.get("last").map(|last| last.parse::<u32>()) {
+            None => 0,
+            Some(Ok(last)) => last,
+            Some(Err(_)) => return Err("invalid query parameter 'last'"),
+        };
+        Ok(Self {
+            code: code.clone(),
+            resume: params.get("resume").cloned(),
+            last,
+        })
+    }
 }
 
 async fn signal_handler(
     extract::State(state): extract::State<Arc<State>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     Query(params): Query<HashMap<String, String>>,
     ws: WebSocketUpgrade,
 ) -> impl IntoResponse {
-    let code = params.get("code");
-    if code.is_none() {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(json!({"error": "missing query parameter 'code'"})),
-        )
-            .into_response();
+    let SignalParams { code, resume, last } = match SignalParams::parse(&params) {
+        Ok(params) => params,
+        Err(message) => return bad_request(message),
+    };
+    let max_frame_size = state.config.limits.max_frame_size;
+    ws.protocols(signal::Encoding::PROTOCOLS)
+        .max_frame_size(max_frame_size)
+        .max_message_size(max_frame_size)
+        .on_upgrade(async move |socket| {
+            signal_handler_upgrade(state, socket, addr.ip(), code, resume, last).await;
+        })
+}
+
+async fn signal_handler_upgrade(
+    state: Arc<State>,
+    mut socket: WebSocket,
+    client: IpAddr,
+    code: String,
+    resume: Option<String>,
+    last: u32,
+) {
+    let hello = tokio::time::timeout(state.config.join_timeout, signal::handshake(&mut socket));
+    match hello.await {
+        Ok(Ok(true)) => {}
+        Ok(Ok(false)) => return,
+        Ok(Err(e)) => {
+            println!("{}", e);
+            return;
+        }
+        Err(_) => {
+            let reason = "hello timed out".to_string();
+            if let Err(e) = signal::close(&mut socket, signal::CLOSE_JOIN_TIMEOUT, reason).await {
+                println!("{}", e);
+            }
+            return;
+        }
     }
-    let code = code.unwrap();
-    if !code::is_valid(code) {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(json!({"error": "invalid query parameter 'code'"})),
-        )
-            .into_response();
+    connect(state, socket, client, code, resume, last).await;
+}
+
+/// Starts a session on a handshaken signaling connection, or resumes the one `resume` names.
+pub async fn connect<C: Connection>(
+    state: Arc<State>,
+    conn: C,
+    client: IpAddr,
+    code: String,
+    resume: Option<String>,
+    last: u32,
+) {
+    let result = match resume {
+        Some(token) => resume_session(state, conn, code, token, last).await,
+        None => start_session(state, conn, client, code).await,
+    };
+    if let Err(e) = result {
+        println!("{}", e);
     }
-    let code = code.clone();
-    ws.on_upgrade(async move |socket| {
-        signal_handler_upgrade(state, socket, code).await;
-    })
 }
 
-async fn signal_handler_upgrade(state: Arc<State>, socket: WebSocket, code: String) {
-    let (error_tx, mut error_rx) = error::channel();
-    let (signal_tx, mut signal_rx) = signal::channel(socket, error_tx.clone());
+async fn resume_session<C: Connection>(
+    state: Arc<State>,
+    conn: C,
+    code: String,
+    token: String,
+    last: u32,
+) -> error::Result<()> {
+    let session = match state.get_session(&token) {
+        Some(session) if session.code() == code && session.mode() == Mode::Signal => session,
+        _ => {
+            return conn
+                .reject(signal::CLOSE_RESUME_FAILED, "unknown resume token".into())
+                .await;
+        }
+    };
+    if let Some((signal_rx, generation)) = session.attach(conn, last).await? {
+        spawn_receiver(session, signal_rx, generation);
+    }
+    Ok(())
+}
 
-    let session = match Session::new(state, code, signal_tx.clone()).await {
-        Ok(s) => s,
-        Err(e) => {
-            println!("{}", e);
-            return;
+async fn start_session<C: Connection>(
+    state: Arc<State>,
+    conn: C,
+    client: IpAddr,
+    code: String,
+) -> error::Result<()> {
+    let (error_tx, error_rx) = error::channel();
+    let mut signal_tx = signal::Sender::new(&state.config);
+    let Some(mut signal_rx) = signal_tx.attach(conn, 0).await? else {
+        return Ok(());
+    };
+
+    let (id, admission) =
+        match tokio::time::timeout(state.config.join_timeout, signal_rx.recv()).await {
+            Err(_) => {
+                return signal_tx
+                    .close(signal::CLOSE_JOIN_TIMEOUT, "join timed out".into())
+                    .await;
+            }
+            Ok(Ok(None)) => return Ok(()),
+            Ok(Ok(Some(envelope))) => (
+                Some(envelope.id),
+                match envelope.message {
+                    PeerMessage::Join(join) => Admission::from_join(join, client, &state.config),
+                    _ => Err(Error::Join("expected join")),
+                },
+            ),
+            Ok(Err(e)) => (None, Err(e)),
+        };
+    let admission = match admission {
+        Ok(mut admission) => {
+            match Session::knock(&state, &code, &mut admission, &mut signal_tx).await {
+                Ok(None) => Ok(admission),
+                Ok(Some((knock, decision))) => {
+                    let admitted = wait_in_lobby(&mut signal_tx, &mut signal_rx, decision).await;
+                    match admitted {
+                        Some(true) => Ok(admission),
+                        Some(false) => {
+                            return signal_tx
+                                .close(signal::CLOSE_DENIED, "denied by a moderator".into())
+                                .await;
+                        }
+                        None => return Session::withdraw_knock(&state, &code, knock).await,
+                    }
+                }
+                Err(e) => Err(e),
+            }
+        }
+        Err(e) => Err(e),
+    };
+    let session = match admission {
+        Ok(admission) => {
+            Session::new(
+                state,
+                code,
+                Mode::Signal,
+                admission,
+                signal_tx.clone(),
+                error_tx.clone(),
+            )
+            .await
         }
+        Err(e) => Err(e),
+    };
+    let session = match session {
+        Ok(s) => s,
+        Err(e) => return reject_join(&mut signal_tx, id, &e).await,
     };
+    serve_session(&session, error_rx).await;
+    spawn_receiver(session, signal_rx, 0);
+    Ok(())
+}
+
+/// Holds a connection whose join knocked until a moderator decides on it, which is
+/// `None` if the client goes away first. Nothing but leaving is accepted meanwhile.
+async fn wait_in_lobby(
+    signal_tx: &mut signal::Sender,
+    signal_rx: &mut impl signal::Receiver,
+    mut decision: oneshot::Receiver<bool>,
+) -> Option<bool> {
+    loop {
+        let envelope = tokio::select! {
+            admitted = &mut decision => return Some(admitted.unwrap_or(false)),
+            envelope = signal_rx.recv() => envelope,
+        };
+        let (id, error) = match envelope {
+            Ok(None) => return None,
+            Ok(Some(envelope)) => (
+                Some(envelope.id),
+                Error::Forbidden("send messages from the lobby"),
+            ),
+            Err(e) => (None, e),
+        };
+        report_error(signal_tx, id, &error).await;
+    }
+}
+
+/// Wires a new session's peer connection up to the room and tears the session
+/// down once it reports a fatal error. Shared by every way of joining a room.
+pub async fn serve_session(session: &Session, mut error_rx: error::Receiver) {
+    let signal_tx = session.signal_tx();
+    let error_tx = session.error_tx();
 
     let session1 = session.clone();
+    let mut signal_tx1 = signal_tx.clone();
     tokio::spawn(async move {
         while let Some(error) = error_rx.recv().await {
-            println!("{}", error);
+            if report_error(&mut signal_tx1, None, &error).await {
+                break;
+            }
         }
+        // Errors reported while the session leaves have nowhere to go but the log.
+        drop(error_rx);
         if let Err(error1) = session1.leave().await {
             println!("{}", error1);
         }
@@ -98,25 +404,41 @@ async fn signal_handler_upgrade(state: Arc<State>, socket: WebSocket, code: Stri
     let (track_tx, mut track_rx) = track::channel(session.peer_id(), error_tx.clone());
 
     let session1 = session.clone();
+    let session3 = session.clone();
     let signal_tx1 = signal_tx.clone();
     let error_tx1 = error_tx.clone();
     let error_tx2 = error_tx.clone();
     session
         .on(SessionHdlrFns {
-            connected: Box::new(move || {
+            state: Box::new(move |state| {
                 let session2 = session1.clone();
                 let error_tx2 = error_tx1.clone();
                 Box::pin(async move {
-                    error_tx2.send(session2.handle_connected()).await;
+                    session2.watch_connection(state);
+                    match state {
+                        RTCPeerConnectionState::Connected => {
+                            error_tx2.send(session2.handle_connected()).await;
+                        }
+                        RTCPeerConnectionState::Disconnected | RTCPeerConnectionState::Failed => {
+                            error_tx2.send(session2.restart_ice()).await;
+                        }
+                        _ => {}
+                    }
                 })
             }),
             candidate: Box::new(move |candidate| {
+                let session4 = session3.clone();
                 let mut signal_tx2 = signal_tx1.clone();
                 let error_tx3 = error_tx2.clone();
                 Box::pin(async move {
-                    error_tx3
-                        .send(signal_tx2.send(ServerMessage::Candidate(candidate)))
-                        .await;
+                    match candidate {
+                        Some(candidate) => {
+                            error_tx3
+                                .send(signal_tx2.send(ServerMessage::Candidate(candidate)))
+                                .await;
+                        }
+                        None => error_tx3.send(session4.send_end_of_candidates()).await,
+                    }
                 })
             }),
             track: Box::new(move |track_remote| {
@@ -129,19 +451,64 @@ async fn signal_handler_upgrade(state: Arc<State>, socket: WebSocket, code: Stri
     let session1 = session.clone();
     let error_tx1 = error_tx.clone();
     error_tx1.spawn(async move {
-        while let Some(message) = signal_rx.recv().await? {
-            session1.handle_message(message).await?;
+        while let Some(track) = track_rx.recv().await {
+            session1.handle_track(track).await?;
         }
-        session1.leave().await?;
         Ok(())
     });
+}
 
-    let session1 = session.clone();
-    let error_tx1 = error_tx.clone();
-    error_tx1.spawn(async move {
-        while let Some(track) = track_rx.recv().await {
-            session1.handle_track(track).await?;
+fn spawn_receiver(session: Session, mut signal_rx: impl signal::Receiver, generation: u32) {
+    let mut signal_tx = session.signal_tx();
+    session.error_tx().spawn(async move {
+        loop {
+            let (id, error) = match signal_rx.recv().await {
+                Ok(Some(envelope)) => {
+                    let id = envelope.id;
+                    match session.handle_message(envelope).await {
+                        Ok(()) => {
+                            signal_tx.send(ServerMessage::Ack(id)).await?;
+                            continue;
+                        }
+                        Err(e) => (Some(id), e),
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => (None, e),
+            };
+            if report_error(&mut signal_tx, id, &error).await {
+                return session.leave().await;
+            }
         }
+        session.detach(generation, signal_rx.closed());
         Ok(())
     });
 }
+
+/// Tells the client why its join failed and closes the connection.
+async fn reject_join(
+    signal_tx: &mut signal::Sender,
+    id: Option<u32>,
+    error: &Error,
+) -> error::Result<()> {
+    println!("{}", error);
+    signal_tx.send(error.to_message(id)).await?;
+    signal_tx
+        .close(signal::CLOSE_JOIN_REJECTED, "join rejected".into())
+        .await
+}
+
+/// Reports the error to the client and closes the socket when it is fatal.
+/// Returns whether the session has to be torn down.
+async fn report_error(signal_tx: &mut signal::Sender, id: Option<u32>, error: &Error) -> bool {
+    println!("{}", error);
+    signal_tx.send(error.to_message(id)).await.ok();
+    if !error.is_fatal() {
+        return false;
+    }
+    signal_tx
+        .close(close_code::ERROR, "internal error".into())
+        .await
+        .ok();
+    true
+}
@@ -1,19 +1,31 @@
-use std::sync::Arc;
+use std::sync::{
+    Arc,
+    atomic::{AtomicU32, Ordering},
+};
 
-use tokio::sync::Mutex;
-use webrtc::rtp_transceiver::rtp_codec::RTPCodecType;
+use axum::extract::ws::CloseCode;
+use tokio::sync::{Mutex, OwnedMutexGuard, oneshot};
+use webrtc::{
+    ice_transport::ice_candidate::RTCIceCandidateInit,
+    peer_connection::peer_connection_state::RTCPeerConnectionState,
+    rtp_transceiver::rtp_codec::RTPCodecType, track::track_local::TrackLocal,
+};
 
 use crate::{
-    error::Result,
-    peer::{OnPeerCandidateHdlrFn, OnPeerConnectedHdlrFn, OnPeerTrackHdlrFn},
-    room::Room,
-    signal::{self, PeerMessage, ServerMessage},
+    breakout, code,
+    error::{self, Error, Result, check_limit},
+    peer::{Admission, Mode, OnPeerCandidateHdlrFn, OnPeerStateHdlrFn, OnPeerTrackHdlrFn},
+    room::{self, Room},
+    signal::{
+        self, Connection, Mute, PeerEnvelope, PeerMessage, Role, ServerMessage, ServerMessageId,
+        ServerMessageMute, SetRole,
+    },
     state::State,
     track::Track,
 };
 
 pub struct SessionHdlrFns {
-    pub connected: OnPeerConnectedHdlrFn,
+    pub state: OnPeerStateHdlrFn,
     pub candidate: OnPeerCandidateHdlrFn,
     pub track: OnPeerTrackHdlrFn,
 }
@@ -22,26 +34,214 @@ pub struct SessionHdlrFns {
 pub struct Session {
     state: Arc<State>,
     code: String,
-    room: Arc<Mutex<Room>>,
+    /// The room the peer is in, which changes as it moves between breakout rooms.
+    room: Arc<std::sync::Mutex<Arc<Mutex<Room>>>>,
     peer_id: u32,
+    mode: Mode,
+    token: String,
+    signal_tx: signal::Sender,
+    error_tx: error::Sender,
+    generation: Arc<AtomicU32>,
+    connection_changes: Arc<AtomicU32>,
 }
 
 impl Session {
-    pub async fn new(state: Arc<State>, code: String, signal_tx: signal::Sender) -> Result<Self> {
-        let room = state.get_room(code.clone());
+    pub async fn new(
+        state: Arc<State>,
+        code: String,
+        mode: Mode,
+        mut admission: Admission,
+        signal_tx: signal::Sender,
+        error_tx: error::Sender,
+    ) -> Result<Self> {
+        Self::check_passcode(&state, &code, &mut admission).await?;
+        if !state.take_seat(mode) {
+            return Err(Error::RoomFull);
+        }
+        let room = match state.open_room(code.clone()) {
+            Ok(room) => room,
+            Err(e) => {
+                state.release_seat(mode);
+                return Err(e);
+            }
+        };
         let mut room_guard = room.lock().await;
-        let peer_id = room_guard.add_peer(signal_tx).await?;
-        let peer = room_guard.get_peer(peer_id);
-        for kind in [RTPCodecType::Video, RTPCodecType::Audio] {
-            peer.add_recvonly_transceiver(kind).await?;
+        let admitted = match Self::admit(&state, &mut room_guard, mode, &admission) {
+            Ok(role) => {
+                room_guard
+                    .add_peer(
+                        mode,
+                        role,
+                        admission.profile,
+                        signal_tx.clone(),
+                        error_tx.clone(),
+                    )
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        let peer_id = match admitted {
+            Ok(peer_id) => peer_id,
+            Err(e) => {
+                Self::turn_away(&state, &code, &room_guard, mode);
+                return Err(e);
+            }
+        };
+        let token = code::generate_token();
+        if let Err(e) = Self::greet(&mut room_guard, peer_id, mode, &token).await {
+            // Nobody has been told about the peer yet, so it can be dropped quietly.
+            if let Some(peer) = room_guard.remove_peer(peer_id)
+                && let Err(e1) = peer.close().await
+            {
+                println!("{}", e1);
+            }
+            Self::turn_away(&state, &code, &room_guard, mode);
+            return Err(e);
         }
-        room_guard.add_other_peers_tracks(peer).await?;
         drop(room_guard);
-        Ok(Self {
+        let session = Self {
             state,
             code,
-            room,
+            room: Arc::new(std::sync::Mutex::new(room)),
             peer_id,
+            mode,
+            token,
+            signal_tx,
+            error_tx,
+            generation: Arc::new(AtomicU32::new(0)),
+            connection_changes: Arc::new(AtomicU32::new(0)),
+        };
+        session
+            .state
+            .add_session(session.token.clone(), session.clone());
+        session.watch_connection(RTCPeerConnectionState::New);
+        Ok(session)
+    }
+
+    /// Gives back the seat of a join that didn't make it into the room, and closes the
+    /// room again if the join opened it.
+    fn turn_away(state: &State, code: &str, room: &Room, mode: Mode) {
+        if !room.is_occupied() {
+            state.remove_room(code, room);
+        }
+        state.release_seat(mode);
+    }
+
+    /// Tells a newly added peer who it is and where, and adds the others' tracks.
+    async fn greet(room: &mut Room, peer_id: u32, mode: Mode, token: &str) -> Result<()> {
+        let peer = room.get_peer_mut(peer_id);
+        peer.send_message(ServerMessage::Id(ServerMessageId {
+            id: peer_id,
+            resume_token: token.into(),
+            role: peer.role,
+            permissions: peer.role.permissions(),
+        }))
+        .await?;
+        if mode.publishes() {
+            for kind in [RTPCodecType::Video, RTPCodecType::Audio] {
+                peer.add_recvonly_transceiver(kind).await?;
+            }
+        }
+        let room_info = room.info();
+        room.get_peer_mut(peer_id)
+            .send_message(ServerMessage::Room(room_info))
+            .await?;
+        if let Some(breakouts) = &room.breakouts {
+            let message = ServerMessage::Breakouts(breakouts.info());
+            room.get_peer_mut(peer_id).send_message(message).await?;
+        }
+        let peer = room.get_peer(peer_id);
+        if peer.role.permissions().moderate {
+            room.send_lobby(peer_id).await?;
+        }
+        let peer = room.get_peer(peer_id);
+        room.add_other_peers_tracks(peer).await?;
+        Ok(())
+    }
+
+    /// Checks the join's passcode against the room's once, whether the room is open or
+    /// its code only reserved, without holding the room's lock while it is hashed.
+    async fn check_passcode(state: &State, code: &str, admission: &mut Admission) -> Result<()> {
+        if admission.passcode_checked {
+            return Ok(());
+        }
+        if let Some(passcode) = state.room_passcode(code).await {
+            let attempt = admission.passcode.as_deref();
+            state
+                .check_passcode(passcode, attempt, admission.client)
+                .await?;
+        }
+        admission.passcode_checked = true;
+        Ok(())
+    }
+
+    /// Puts the join in the room's lobby if it has to wait there, after checking its
+    /// passcode, and returns its lobby id and the moderators' decision to come.
+    pub async fn knock(
+        state: &State,
+        code: &str,
+        admission: &mut Admission,
+        signal_tx: &mut signal::Sender,
+    ) -> Result<Option<(u32, oneshot::Receiver<bool>)>> {
+        Self::check_passcode(state, code, admission).await?;
+        let Some(room) = state.find_room(code) else {
+            return Ok(None);
+        };
+        let mut room_guard = room.lock().await;
+        if room_guard.locked {
+            return Err(Error::RoomLocked);
+        }
+        if !room_guard.must_wait(admission, &state.config) {
+            return Ok(None);
+        }
+        admission.admitted = true;
+        signal_tx.send(ServerMessage::Waiting).await?;
+        let knock = room_guard.knock(admission.profile.name.clone()).await?;
+        println!("[{}] knock {} waiting", code, knock.0);
+        Ok(Some(knock))
+    }
+
+    pub async fn withdraw_knock(state: &State, code: &str, id: u32) -> Result<()> {
+        match state.find_room(code) {
+            Some(room) => room.lock().await.withdraw_knock(id).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Decides whether the join gets into the room, and with which role. The peer that
+    /// opens the room sets its metadata, and `/signal` peers host rooms without a host,
+    /// unless the host is out in a breakout room.
+    fn admit(state: &State, room: &mut Room, mode: Mode, admission: &Admission) -> Result<Role> {
+        if room.locked {
+            return Err(Error::RoomLocked);
+        }
+        if room.must_wait(admission, &state.config) {
+            return Err(Error::Forbidden("skip the waiting room"));
+        }
+        let capacity = &state.config.capacity;
+        let room_limit = if mode.publishes() {
+            capacity.room_publishers
+        } else {
+            capacity.room_viewers
+        };
+        if room_limit.is_some_and(|limit| room.occupancy().of(mode) >= limit) {
+            return Err(Error::RoomFull);
+        }
+        if let Mode::View(Some(peer_id)) = mode
+            && !room.has_peer(peer_id)
+        {
+            return Err(Error::UnknownPeer(peer_id));
+        }
+        if !room.is_occupied() {
+            room.creator = Some(admission.profile.name.clone());
+            if let Some(metadata) = &admission.room {
+                room.metadata = metadata.clone();
+            }
+        }
+        Ok(match mode {
+            Mode::View(_) => Role::Viewer,
+            Mode::Signal if !room.has_host() && room.breakouts.is_none() => Role::Host,
+            _ => Role::Participant,
         })
     }
 
@@ -49,69 +249,904 @@ impl Session {
         self.peer_id
     }
 
+    pub fn code(&self) -> &str {
+        &self.code
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    pub fn room(&self) -> Arc<Mutex<Room>> {
+        Arc::clone(&self.room.lock().unwrap())
+    }
+
+    /// Locks the room the peer is in. Moves hold the lock of the room they move the peer
+    /// out of, so once it's taken the peer is known to be in this room or gone.
+    async fn lock_room(&self) -> OwnedMutexGuard<Room> {
+        loop {
+            let room = self.room();
+            let room_guard = Arc::clone(&room).lock_owned().await;
+            if Arc::ptr_eq(&room, &self.room()) {
+                return room_guard;
+            }
+        }
+    }
+
+    /// The peer's role, unless it left.
+    pub async fn role(&self) -> Option<Role> {
+        let room_guard = self.lock_room().await;
+        room_guard.try_get_peer(self.peer_id).map(|peer| peer.role)
+    }
+
+    /// Moves the peer to another room on its existing connections. Both rooms are locked
+    /// in the same order by every move, so that moves in opposite directions can't
+    /// deadlock.
+    pub async fn move_to(&self, to: Arc<Mutex<Room>>) -> Result<()> {
+        loop {
+            let from = self.room();
+            if Arc::ptr_eq(&from, &to) {
+                return Ok(());
+            }
+            let (mut from_guard, mut to_guard) = if Arc::as_ptr(&from) < Arc::as_ptr(&to) {
+                let from_guard = Arc::clone(&from).lock_owned().await;
+                (from_guard, Arc::clone(&to).lock_owned().await)
+            } else {
+                let to_guard = Arc::clone(&to).lock_owned().await;
+                (Arc::clone(&from).lock_owned().await, to_guard)
+            };
+            if !Arc::ptr_eq(&from, &self.room()) {
+                continue;
+            }
+            let Some((peer, moved_out)) = from_guard.move_peer_out(self.peer_id).await else {
+                return Ok(());
+            };
+            *self.room.lock().unwrap() = Arc::clone(&to);
+            let moved_in = to_guard.move_peer_in(peer).await;
+            println!(
+                "[{}] peer {} moved to room {}",
+                self.code, self.peer_id, to_guard.id
+            );
+            self.schedule_room_offers(from, &mut from_guard);
+            self.schedule_room_offers(to, &mut to_guard);
+            return moved_out.and(moved_in);
+        }
+    }
+
+    pub fn signal_tx(&self) -> signal::Sender {
+        self.signal_tx.clone()
+    }
+
+    pub fn error_tx(&self) -> error::Sender {
+        self.error_tx.clone()
+    }
+
+    /// Re-attaches a new signaling connection to the session and returns its receiver
+    /// together with the attachment generation that has to be passed to `detach`.
+    pub async fn attach<C: Connection>(
+        &self,
+        conn: C,
+        last: u32,
+    ) -> Result<Option<(C::Receiver, u32)>> {
+        let Some(signal_rx) = self.signal_tx.attach(conn, last).await? else {
+            return Ok(None);
+        };
+        let generation = self.generation.fetch_add(1, Ordering::Relaxed) + 1;
+        self.signal_tx()
+            .send(ServerMessage::Resumed(self.peer_id))
+            .await?;
+        Ok(Some((signal_rx, generation)))
+    }
+
+    /// Called when the connection of the given attachment goes away. Unless the client
+    /// closed it on purpose, the peer is kept for the resume grace period first.
+    pub fn detach(&self, generation: u32, closed: bool) {
+        let session = self.clone();
+        self.error_tx.clone().spawn(async move {
+            if !closed {
+                tokio::time::sleep(session.state.config.resume_grace).await;
+            }
+            if session.generation.load(Ordering::Relaxed) == generation {
+                session.leave().await?;
+            }
+            Ok(())
+        });
+    }
+
     pub async fn on(&self, fns: SessionHdlrFns) {
-        let room_guard = self.room.lock().await;
-        let peer = room_guard.get_peer(self.peer_id);
-        peer.on_connected(fns.connected);
+        let room_guard = self.lock_room().await;
+        let Some(peer) = room_guard.try_get_peer(self.peer_id) else {
+            return;
+        };
+        peer.on_state(fns.state);
         peer.on_candidate(fns.candidate);
         peer.on_track(fns.track);
     }
 
+    /// Evicts the peer if its connection is still in `state` once the timeout for
+    /// that state runs out. Every call supersedes the previous one.
+    pub fn watch_connection(&self, state: RTCPeerConnectionState) {
+        let change = self.connection_changes.fetch_add(1, Ordering::Relaxed) + 1;
+        let config = &self.state.config;
+        let timeout = match state {
+            RTCPeerConnectionState::New | RTCPeerConnectionState::Connecting => {
+                config.connect_timeout
+            }
+            RTCPeerConnectionState::Disconnected | RTCPeerConnectionState::Failed => {
+                config.disconnect_timeout
+            }
+            _ => return,
+        };
+        let session = self.clone();
+        self.error_tx.clone().spawn(async move {
+            tokio::time::sleep(timeout).await;
+            if session.connection_changes.load(Ordering::Relaxed) != change {
+                return Ok(());
+            }
+            println!(
+                "[{}] peer {} evicted, connection {}",
+                session.code, session.peer_id, state
+            );
+            session
+                .remove(signal::CLOSE_CONNECTION_TIMEOUT, "connection timed out")
+                .await
+        });
+    }
+
     pub async fn handle_connected(&self) -> Result<()> {
-        let room_guard = self.room.lock().await;
+        let mut room_guard = self.lock_room().await;
         let message = ServerMessage::Peers(room_guard.get_server_message_peers(self.peer_id));
-        drop(room_guard);
-        let mut room_guard = self.room.lock().await;
-        let peer = room_guard.get_peer_mut(self.peer_id);
-        peer.send_offer().await?;
+        let Some(peer) = room_guard.try_get_peer_mut(self.peer_id) else {
+            return Ok(());
+        };
+        peer.request_offer();
         peer.send_message(message).await?;
-        if let Some(name) = peer.name.clone() {
-            room_guard.send_joined_peer(self.peer_id, name).await?;
+        if peer.mode.publishes() {
+            let info = peer.info();
+            room_guard.send_joined_peer(info).await?;
         }
+        self.schedule_offers(&mut room_guard);
         Ok(())
     }
 
-    pub async fn handle_message(&self, message: PeerMessage) -> Result<()> {
-        let mut room_guard = self.room.lock().await;
-        let peer = room_guard.get_peer_mut(self.peer_id);
-        match message {
+    /// Messages that arrive after the peer was taken out of the room, e.g. when it was
+    /// kicked, are dropped.
+    pub async fn handle_message(&self, envelope: PeerEnvelope) -> Result<()> {
+        let limits = &self.state.config.limits;
+        let mut room_guard = self.lock_room().await;
+        let Some(peer) = room_guard.try_get_peer_mut(self.peer_id) else {
+            return Ok(());
+        };
+        match envelope.message {
             PeerMessage::Offer(sdp) => {
-                peer.recv_offer(sdp).await?;
+                check_limit("offer sdp", sdp.len(), limits.max_sdp_len)?;
+                peer.recv_offer(envelope.id, sdp).await?;
             }
             PeerMessage::Answer(sdp) => {
-                peer.recv_answer(sdp).await?;
+                check_limit("answer sdp", sdp.len(), limits.max_sdp_len)?;
+                peer.recv_answer(envelope.re, sdp).await?;
             }
             PeerMessage::Candidate(candidate) => {
-                peer.add_candidate(candidate).await?;
+                peer.add_candidate(candidate, limits.max_pending_candidates)
+                    .await?;
             }
-            PeerMessage::Name(name) => {
-                peer.set_name(name);
+            PeerMessage::EndOfCandidates(username_fragment) => {
+                let candidate = RTCIceCandidateInit {
+                    username_fragment,
+                    ..Default::default()
+                };
+                peer.add_candidate(candidate, limits.max_pending_candidates)
+                    .await?;
+            }
+            PeerMessage::IceRestart => {
+                peer.restart_ice().await?;
+            }
+            PeerMessage::Join(_) => {
+                return Err(Error::Join("already joined"));
             }
             PeerMessage::Pli(id) => {
                 room_guard.send_pli(id).await?;
             }
+            PeerMessage::SetRole(SetRole { peer: target, role }) => {
+                Self::check_moderation(&room_guard, self.peer_id, target)?;
+                if role == Role::Host {
+                    return Err(Error::Forbidden("make others host"));
+                }
+                room_guard.set_role(target, role).await?;
+                self.schedule_offers(&mut room_guard);
+            }
+            PeerMessage::Chat(text) => {
+                if !peer.role.permissions().chat {
+                    return Err(Error::Forbidden("chat"));
+                }
+                check_limit("chat message", text.chars().count(), limits.max_chat_len)?;
+                room_guard.send_chat(self.peer_id, text).await?;
+            }
+            PeerMessage::Kick(target) => {
+                Self::check_moderation(&room_guard, self.peer_id, target)?;
+                room_guard
+                    .get_peer_mut(target)
+                    .send_message(ServerMessage::Kicked(self.peer_id))
+                    .await?;
+                drop(room_guard);
+                let sessions = self.state.room_sessions(&self.code);
+                if let Some(session) = sessions.iter().find(|s| s.peer_id == target) {
+                    session.remove(signal::CLOSE_KICKED, "kicked").await?;
+                }
+            }
+            PeerMessage::Mute(Mute { peer: target, kind }) => {
+                Self::check_moderation(&room_guard, self.peer_id, target)?;
+                let mute = ServerMessageMute {
+                    peer: target,
+                    kind,
+                    by: self.peer_id,
+                };
+                room_guard.set_muted(mute, true).await?;
+                self.schedule_offers(&mut room_guard);
+            }
+            PeerMessage::AskUnmute(Mute { peer: target, kind }) => {
+                Self::check_moderation(&room_guard, self.peer_id, target)?;
+                let mute = ServerMessageMute {
+                    peer: target,
+                    kind,
+                    by: self.peer_id,
+                };
+                room_guard
+                    .get_peer_mut(target)
+                    .send_message(ServerMessage::UnmuteRequested(mute))
+                    .await?;
+            }
+            PeerMessage::Unmute(kind) => {
+                let mute = ServerMessageMute {
+                    peer: self.peer_id,
+                    kind,
+                    by: self.peer_id,
+                };
+                room_guard.set_muted(mute, false).await?;
+                self.schedule_offers(&mut room_guard);
+            }
+            PeerMessage::SetWaitingRoom(on) => {
+                if !peer.role.permissions().moderate {
+                    return Err(Error::Forbidden("change the waiting room"));
+                }
+                room_guard.set_waiting_room(on).await?;
+            }
+            PeerMessage::SetLocked(locked) => {
+                if !peer.role.permissions().moderate {
+                    return Err(Error::Forbidden("lock the room"));
+                }
+                room_guard.set_locked(locked).await?;
+                println!("[{}] room locked: {}", self.code, locked);
+            }
+            PeerMessage::Admit(id) => {
+                if !peer.role.permissions().moderate {
+                    return Err(Error::Forbidden("admit"));
+                }
+                room_guard.answer_knock(id, true).await?;
+            }
+            PeerMessage::Deny(id) => {
+                if !peer.role.permissions().moderate {
+                    return Err(Error::Forbidden("deny"));
+                }
+                room_guard.answer_knock(id, false).await?;
+            }
+            PeerMessage::OpenBreakouts(open) => {
+                Self::check_breakouts(&room_guard, self.peer_id)?;
+                check_limit(
+                    "breakout rooms",
+                    open.names.len(),
+                    limits.max_breakout_rooms,
+                )?;
+                for name in &open.names {
+                    check_limit(
+                        "breakout room name",
+                        name.chars().count(),
+                        limits.max_name_len,
+                    )?;
+                }
+                if room_guard.breakouts.is_some() {
+                    return Err(Error::Forbidden(
+                        "open breakout rooms while others are open",
+                    ));
+                }
+                breakout::open(&self.state, &self.code, &mut room_guard, open).await?;
+            }
+            PeerMessage::AssignBreakout(assign) => {
+                Self::check_breakouts(&room_guard, self.peer_id)?;
+                drop(room_guard);
+                breakout::assign(&self.state, &self.code, assign).await?;
+            }
+            PeerMessage::ShuffleBreakouts => {
+                Self::check_breakouts(&room_guard, self.peer_id)?;
+                drop(room_guard);
+                breakout::shuffle(&self.state, &self.code).await?;
+            }
+            PeerMessage::CloseBreakouts => {
+                Self::check_breakouts(&room_guard, self.peer_id)?;
+                drop(room_guard);
+                breakout::close(&self.state, &self.code, None).await?;
+            }
+            PeerMessage::SetRoom(metadata) => {
+                if !peer.role.permissions().moderate {
+                    return Err(Error::Forbidden("change the room"));
+                }
+                room::check_metadata(&metadata, limits)?;
+                room_guard.set_metadata(metadata).await?;
+                println!("[{}] room metadata changed", self.code);
+            }
+            PeerMessage::EndMeeting => {
+                if !peer.role.permissions().moderate {
+                    return Err(Error::Forbidden("end the meeting"));
+                }
+                println!("[{}] meeting ended by peer {}", self.code, self.peer_id);
+                drop(room_guard);
+                if let Some(main) = self.state.find_room(&self.code) {
+                    main.lock().await.breakouts = None;
+                }
+                // Everyone, breakout rooms included, is disconnected before anyone
+                // leaves, so that nobody is told about the others leaving.
+                let sessions = self.state.room_sessions(&self.code);
+                for session in &sessions {
+                    let mut signal_tx = session.signal_tx();
+                    signal_tx
+                        .send(ServerMessage::MeetingEnded(self.peer_id))
+                        .await?;
+                    signal_tx
+                        .close(signal::CLOSE_MEETING_ENDED, "meeting ended".into())
+                        .await?;
+                }
+                for session in &sessions {
+                    session.leave().await?;
+                }
+                // Left empty while everyone was out in the breakout rooms.
+                if let Some(main) = self.state.find_room(&self.code) {
+                    let main_guard = main.lock().await;
+                    if !main_guard.is_occupied() {
+                        self.state.remove_room(&self.code, &main_guard);
+                    }
+                }
+            }
         }
         Ok(())
     }
 
+    /// Checks that the peer may moderate `target`, which can't be the host.
+    fn check_moderation(room: &Room, peer_id: u32, target: u32) -> Result<()> {
+        if !room.get_peer(peer_id).role.permissions().moderate {
+            return Err(Error::Forbidden("moderate"));
+        }
+        if !room.has_peer(target) {
+            return Err(Error::UnknownPeer(target));
+        }
+        if room.get_peer(target).role == Role::Host {
+            return Err(Error::Forbidden("moderate the host"));
+        }
+        Ok(())
+    }
+
+    /// Checks that the peer may manage breakout rooms, which is done from the main room.
+    fn check_breakouts(room: &Room, peer_id: u32) -> Result<()> {
+        if !room.get_peer(peer_id).role.permissions().moderate {
+            return Err(Error::Forbidden("manage breakout rooms"));
+        }
+        if room.breakout.is_some() {
+            return Err(Error::Forbidden(
+                "manage breakout rooms from a breakout room",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Answers an offer made outside of signaling and returns the complete answer.
+    /// The room isn't locked while ICE gathers.
+    pub async fn accept_offer(&self, sdp: String) -> Result<String> {
+        check_limit("offer sdp", sdp.len(), self.state.config.limits.max_sdp_len)?;
+        let mut gathering_complete = self
+            .lock_room()
+            .await
+            .try_get_peer_mut(self.peer_id)
+            .ok_or(Error::UnknownPeer(self.peer_id))?
+            .accept_offer(sdp)
+            .await?;
+        gathering_complete.recv().await;
+        self.lock_room()
+            .await
+            .try_get_peer(self.peer_id)
+            .ok_or(Error::UnknownPeer(self.peer_id))?
+            .complete_answer()
+            .await
+    }
+
+    pub async fn add_candidate(&self, candidate: RTCIceCandidateInit) -> Result<()> {
+        let max_pending = self.state.config.limits.max_pending_candidates;
+        let mut room_guard = self.lock_room().await;
+        room_guard
+            .try_get_peer_mut(self.peer_id)
+            .ok_or(Error::UnknownPeer(self.peer_id))?
+            .add_candidate(candidate, max_pending)
+            .await
+    }
+
+    /// Restarts ICE so the connection can recover from a network change.
+    pub async fn restart_ice(&self) -> Result<()> {
+        let mut room_guard = self.lock_room().await;
+        match room_guard.try_get_peer_mut(self.peer_id) {
+            Some(peer) => peer.restart_ice().await,
+            None => Ok(()),
+        }
+    }
+
+    pub async fn send_end_of_candidates(&self) -> Result<()> {
+        let room_guard = self.lock_room().await;
+        let Some(peer) = room_guard.try_get_peer(self.peer_id) else {
+            return Ok(());
+        };
+        if !peer
+            .profile
+            .capabilities
+            .supports(signal::FEATURE_END_OF_CANDIDATES)
+        {
+            return Ok(());
+        }
+        let ufrag = peer.local_ice_ufrag().await;
+        self.signal_tx()
+            .send(ServerMessage::EndOfCandidates(ufrag))
+            .await
+    }
+
     pub async fn handle_track(&self, track: Track) -> Result<()> {
-        let mut room_guard = self.room.lock().await;
-        let peer = room_guard.get_peer_mut(self.peer_id);
+        let mut room_guard = self.lock_room().await;
+        let Some(peer) = room_guard.try_get_peer_mut(self.peer_id) else {
+            return Ok(());
+        };
         let track_local = Arc::clone(&track.inner.inner);
+        let forwards = peer.forwards(&track.inner);
         peer.set_track(track);
-        let send_offer = peer.is_audio_and_video();
+        if !forwards {
+            // Kept, so that it is forwarded once the peer's role or mute changes.
+            println!(
+                "[{}] peer {} track {} not forwarded",
+                self.code,
+                self.peer_id,
+                track_local.id()
+            );
+            return Ok(());
+        }
+        let request_offer = peer.is_audio_and_video();
         room_guard
-            .add_peer_track_to_others(self.peer_id, track_local, send_offer)
+            .add_peer_track_to_others(self.peer_id, track_local, request_offer)
             .await?;
+        self.schedule_offers(&mut room_guard);
         Ok(())
     }
 
     pub async fn leave(&self) -> Result<()> {
-        let mut room_guard = self.room.lock().await;
-        let left = room_guard.handle_peer_leave(self.peer_id).await?;
-        if left && room_guard.peers.len() == 0 {
-            self.state.remove_room(&self.code);
+        self.state.remove_session(&self.token);
+        let mut room_guard = self.lock_room().await;
+        let joined = room_guard.has_peer(self.peer_id);
+        let left = room_guard.handle_peer_leave(self.peer_id).await;
+        if joined {
+            self.state.release_seat(self.mode);
         }
-        Ok(())
+        // A main room is kept while its breakout rooms are open, for everyone to return to.
+        if joined && !room_guard.is_occupied() && room_guard.breakout.is_none() {
+            self.state.remove_room(&self.code, &room_guard);
+        }
+        self.schedule_offers(&mut room_guard);
+        left
+    }
+
+    /// Takes the peer out of the room on the server's initiative, closing its signaling
+    /// connection with the reason.
+    pub async fn remove(&self, code: CloseCode, reason: &str) -> Result<()> {
+        self.signal_tx().close(code, reason.into()).await?;
+        self.leave().await
+    }
+
+    /// Flushes the room's requested offers after the debounce window, so that
+    /// changes arriving close together cost each peer a single renegotiation.
+    fn schedule_offers(&self, room_guard: &mut Room) {
+        self.schedule_room_offers(self.room(), room_guard);
+    }
+
+    fn schedule_room_offers(&self, room: Arc<Mutex<Room>>, room_guard: &mut Room) {
+        if room_guard.offers_scheduled || !room_guard.has_requested_offers() {
+            return;
+        }
+        room_guard.offers_scheduled = true;
+        let debounce = self.state.config.offer_debounce;
+        tokio::spawn(async move {
+            tokio::time::sleep(debounce).await;
+            let failures = room.lock().await.send_offers().await;
+            for (error_tx, e) in failures {
+                error_tx.report(e).await;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        config::{Capacity, Config},
+        signal::{Capabilities, Join, MediaKind, RoomMetadata},
+        testing::{connect, envelope, join, join_message, reserve, try_join},
+    };
+
+    async fn room_with(config: Config) -> (Arc<State>, String) {
+        let state = Arc::new(State::new(config));
+        let code = reserve(&state).await;
+        (state, code)
+    }
+
+    #[tokio::test]
+    async fn kicked_peer_is_left_alone() {
+        let state = Arc::new(State::new(Config::default()));
+        let code = reserve(&state).await;
+        let host = join(&state, &code, "host").await;
+        let guest = join(&state, &code, "guest").await;
+
+        let kick = PeerEnvelope {
+            id: 1,
+            re: None,
+            message: PeerMessage::Kick(guest.peer_id()),
+        };
+        host.handle_message(kick).await.unwrap();
+        assert_eq!(guest.role().await, None);
+
+        let chat = PeerEnvelope {
+            id: 1,
+            re: None,
+            message: PeerMessage::Chat("still here?".into()),
+        };
+        guest.handle_message(chat).await.unwrap();
+        guest.handle_connected().await.unwrap();
+        guest.restart_ice().await.unwrap();
+        guest.send_end_of_candidates().await.unwrap();
+        assert!(matches!(
+            guest.accept_offer("v=0".into()).await,
+            Err(Error::UnknownPeer(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn passcode_is_checked_for_the_first_join_too() {
+        let state = Arc::new(State::new(Config::default()));
+        let ttl = Duration::from_secs(60);
+        let code = state
+            .reserve_room(ttl, Some("secret".into()))
+            .await
+            .unwrap();
+        let with = |passcode: Option<&str>| Join {
+            passcode: passcode.map(Into::into),
+            ..join_message("guest")
+        };
+
+        assert!(matches!(
+            try_join(&state, &code, with(None)).await,
+            Err(Error::Unauthorized("passcode required"))
+        ));
+        assert!(matches!(
+            try_join(&state, &code, with(Some("guess"))).await,
+            Err(Error::Unauthorized("wrong passcode"))
+        ));
+        assert!(state.find_room(&code).is_none());
+
+        let host = try_join(&state, &code, with(Some("secret"))).await.unwrap();
+        host.leave().await.unwrap();
+        assert!(state.find_room(&code).is_none());
+        assert!(matches!(
+            try_join(&state, &code, with(None)).await,
+            Err(Error::Unauthorized("passcode required"))
+        ));
+        try_join(&state, &code, with(Some("secret"))).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn kicked_peer_is_told_and_disconnected() {
+        let (state, code) = room_with(Config::default()).await;
+        let host = join(&state, &code, "host").await;
+        let guest = join(&state, &code, "guest").await;
+        let mut guest_messages = connect(&guest).await;
+        guest_messages.take().await;
+
+        let kick = || envelope(PeerMessage::Kick(guest.peer_id()));
+        assert!(matches!(
+            guest.handle_message(kick()).await,
+            Err(Error::Forbidden("moderate"))
+        ));
+        host.handle_message(kick()).await.unwrap();
+        let messages = guest_messages.take().await;
+        assert!(matches!(messages[..], [ServerMessage::Kicked(by)] if by == host.peer_id()));
+        assert_eq!(guest_messages.closed().await, Some(signal::CLOSE_KICKED));
+        assert_eq!(guest.role().await, None);
+        assert!(state.find_room(&code).is_some());
+    }
+
+    #[tokio::test]
+    async fn moderators_mute_others_but_not_the_host() {
+        let (state, code) = room_with(Config::default()).await;
+        let host = join(&state, &code, "host").await;
+        let guest = join(&state, &code, "guest").await;
+        let co_host = join(&state, &code, "co-host").await;
+        let set_role = SetRole {
+            peer: co_host.peer_id(),
+            role: Role::CoHost,
+        };
+        host.handle_message(envelope(PeerMessage::SetRole(set_role)))
+            .await
+            .unwrap();
+        let mut guest_messages = connect(&guest).await;
+        guest_messages.take().await;
+        let video = |peer: &Session| Mute {
+            peer: peer.peer_id(),
+            kind: MediaKind::Video,
+        };
+        let is_muted = async |peer: &Session| {
+            let room = peer.room();
+            let room_guard = room.lock().await;
+            let muted = &room_guard.get_peer(peer.peer_id()).muted;
+            muted.contains(&MediaKind::Video)
+        };
+
+        co_host
+            .handle_message(envelope(PeerMessage::Mute(video(&guest))))
+            .await
+            .unwrap();
+        assert!(is_muted(&guest).await);
+        let messages = guest_messages.take().await;
+        assert!(matches!(
+            messages[..],
+            [ServerMessage::Muted(ServerMessageMute { peer, kind: MediaKind::Video, by })]
+                if peer == guest.peer_id() && by == co_host.peer_id()
+        ));
+
+        host.handle_message(envelope(PeerMessage::AskUnmute(video(&guest))))
+            .await
+            .unwrap();
+        let messages = guest_messages.take().await;
+        assert!(matches!(
+            messages[..],
+            [ServerMessage::UnmuteRequested(ServerMessageMute { by, .. })] if by == host.peer_id()
+        ));
+        guest
+            .handle_message(envelope(PeerMessage::Unmute(MediaKind::Video)))
+            .await
+            .unwrap();
+        assert!(!is_muted(&guest).await);
+        let messages = guest_messages.take().await;
+        assert!(matches!(messages[..], [ServerMessage::Unmuted(_)]));
+
+        assert!(matches!(
+            co_host
+                .handle_message(envelope(PeerMessage::Mute(video(&host))))
+                .await,
+            Err(Error::Forbidden("moderate the host"))
+        ));
+        assert!(matches!(
+            guest
+                .handle_message(envelope(PeerMessage::Mute(video(&co_host))))
+                .await,
+            Err(Error::Forbidden("moderate"))
+        ));
+        assert!(!is_muted(&host).await);
+        assert!(!is_muted(&co_host).await);
+    }
+
+    #[tokio::test]
+    async fn ending_the_meeting_disconnects_everyone() {
+        let (state, code) = room_with(Config::default()).await;
+        let host = join(&state, &code, "host").await;
+        let guest = join(&state, &code, "guest").await;
+        let mut host_messages = connect(&host).await;
+        let mut guest_messages = connect(&guest).await;
+        host_messages.take().await;
+        guest_messages.take().await;
+
+        assert!(matches!(
+            guest
+                .handle_message(envelope(PeerMessage::EndMeeting))
+                .await,
+            Err(Error::Forbidden("end the meeting"))
+        ));
+        host.handle_message(envelope(PeerMessage::EndMeeting))
+            .await
+            .unwrap();
+        for messages in [&mut host_messages, &mut guest_messages] {
+            let ended = messages.take().await;
+            assert!(matches!(ended[..], [ServerMessage::MeetingEnded(by)] if by == host.peer_id()));
+            assert_eq!(messages.closed().await, Some(signal::CLOSE_MEETING_ENDED));
+        }
+        assert!(state.find_room(&code).is_none());
+        assert!(state.room_sessions(&code).is_empty());
+    }
+
+    #[tokio::test]
+    async fn roles_decide_what_peers_may_do() {
+        let (state, code) = room_with(Config::default()).await;
+        let host = join(&state, &code, "host").await;
+        let guest = join(&state, &code, "guest").await;
+        assert_eq!(host.role().await, Some(Role::Host));
+        assert_eq!(guest.role().await, Some(Role::Participant));
+        let mut guest_messages = connect(&guest).await;
+        guest_messages.take().await;
+
+        let make_viewer = SetRole {
+            peer: guest.peer_id(),
+            role: Role::Viewer,
+        };
+        assert!(matches!(
+            guest
+                .handle_message(envelope(PeerMessage::SetRole(make_viewer)))
+                .await,
+            Err(Error::Forbidden("moderate"))
+        ));
+        for message in [
+            PeerMessage::SetLocked(true),
+            PeerMessage::SetWaitingRoom(true),
+            PeerMessage::SetRoom(RoomMetadata::default()),
+        ] {
+            assert!(matches!(
+                guest.handle_message(envelope(message)).await,
+                Err(Error::Forbidden(_))
+            ));
+        }
+        let make_host = SetRole {
+            peer: guest.peer_id(),
+            role: Role::Host,
+        };
+        assert!(matches!(
+            host.handle_message(envelope(PeerMessage::SetRole(make_host)))
+                .await,
+            Err(Error::Forbidden("make others host"))
+        ));
+
+        host.handle_message(envelope(PeerMessage::SetRole(make_viewer)))
+            .await
+            .unwrap();
+        assert_eq!(guest.role().await, Some(Role::Viewer));
+        let messages = guest_messages.take().await;
+        let [ServerMessage::PeerUpdated(info)] = &messages[..] else {
+            panic!("unexpected messages {:?}", messages);
+        };
+        assert_eq!(info.role, Role::Viewer);
+        assert!(!info.permissions.publish_audio && !info.permissions.publish_video);
+        assert!(info.permissions.chat);
+        guest
+            .handle_message(envelope(PeerMessage::Chat("hi".into())))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn locked_room_turns_joins_away() {
+        let (state, code) = room_with(Config::default()).await;
+        let host = join(&state, &code, "host").await;
+        let guest = join(&state, &code, "guest").await;
+        let mut guest_messages = connect(&guest).await;
+        guest_messages.take().await;
+
+        host.handle_message(envelope(PeerMessage::SetLocked(true)))
+            .await
+            .unwrap();
+        let messages = guest_messages.take().await;
+        assert!(matches!(messages[..], [ServerMessage::Locked(true)]));
+        assert!(matches!(
+            try_join(&state, &code, join_message("late")).await,
+            Err(Error::RoomLocked)
+        ));
+        host.handle_message(envelope(PeerMessage::SetLocked(false)))
+            .await
+            .unwrap();
+        try_join(&state, &code, join_message("late")).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn full_room_turns_joins_away() {
+        let config = Config {
+            capacity: Capacity {
+                room_publishers: Some(2),
+                ..Capacity::default()
+            },
+            ..Config::default()
+        };
+        let (state, code) = room_with(config).await;
+        let host = join(&state, &code, "host").await;
+        let guest = join(&state, &code, "guest").await;
+        assert!(matches!(
+            try_join(&state, &code, join_message("third")).await,
+            Err(Error::RoomFull)
+        ));
+        assert_eq!(state.occupancy().publishers, 2);
+        guest.leave().await.unwrap();
+        assert_eq!(state.occupancy().publishers, 1);
+        try_join(&state, &code, join_message("third"))
+            .await
+            .unwrap();
+        host.leave().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn room_metadata_is_set_by_the_opener_and_moderators() {
+        let (state, code) = room_with(Config::default()).await;
+        let metadata = |title: &str| RoomMetadata {
+            title: Some(title.into()),
+            ..RoomMetadata::default()
+        };
+        let opener = Join {
+            room: Some(metadata("Standup")),
+            ..join_message("host")
+        };
+        let host = try_join(&state, &code, opener).await.unwrap();
+        let late = Join {
+            room: Some(metadata("Taken over")),
+            ..join_message("guest")
+        };
+        let guest = try_join(&state, &code, late).await.unwrap();
+        let mut guest_messages = connect(&guest).await;
+        let messages = guest_messages.take().await;
+        let room = messages.iter().find_map(|message| match message {
+            ServerMessage::Room(room) => Some(room),
+            _ => None,
+        });
+        let room = room.expect("room metadata sent on joining");
+        assert_eq!(room.metadata, metadata("Standup"));
+        assert_eq!(room.creator.as_deref(), Some("host"));
+
+        assert!(matches!(
+            guest
+                .handle_message(envelope(PeerMessage::SetRoom(metadata("Mine"))))
+                .await,
+            Err(Error::Forbidden("change the room"))
+        ));
+        let too_long = metadata(&"x".repeat(state.config.limits.max_title_len + 1));
+        assert!(matches!(
+            host.handle_message(envelope(PeerMessage::SetRoom(too_long)))
+                .await,
+            Err(Error::Limit { .. })
+        ));
+        host.handle_message(envelope(PeerMessage::SetRoom(metadata("Retro"))))
+            .await
+            .unwrap();
+        let messages = guest_messages.take().await;
+        assert!(matches!(
+            &messages[..],
+            [ServerMessage::Room(room)] if room.metadata == metadata("Retro")
+                && room.creator.as_deref() == Some("host")
+        ));
+    }
+
+    #[tokio::test]
+    async fn end_of_candidates_only_reaches_clients_that_understand_it() {
+        let (state, code) = room_with(Config::default()).await;
+        let aware = Join {
+            capabilities: Capabilities {
+                features: vec![signal::FEATURE_END_OF_CANDIDATES.into()],
+                ..Capabilities::default()
+            },
+            ..join_message("aware")
+        };
+        let aware = try_join(&state, &code, aware).await.unwrap();
+        let unaware = join(&state, &code, "unaware").await;
+        let mut aware_messages = connect(&aware).await;
+        let mut unaware_messages = connect(&unaware).await;
+        aware_messages.take().await;
+        unaware_messages.take().await;
+
+        aware.send_end_of_candidates().await.unwrap();
+        unaware.send_end_of_candidates().await.unwrap();
+        let messages = aware_messages.take().await;
+        assert!(matches!(messages[..], [ServerMessage::EndOfCandidates(_)]));
+        assert!(unaware_messages.take().await.is_empty());
+
+        let end = PeerMessage::EndOfCandidates(Some("ufrag".into()));
+        aware.handle_message(envelope(end)).await.unwrap();
     }
 }
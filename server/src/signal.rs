@@ -1,85 +1,800 @@
-use crate::error::{self, Result};
-use axum::extract::ws::{Message as WebSocketMessage, WebSocket};
+use std::{collections::VecDeque, sync::Arc, time::Duration};
+
+use crate::{config::Config, error::Result, schema};
+use axum::extract::ws::{CloseCode, CloseFrame, Message as WebSocketMessage, WebSocket};
 use futures::{
     sink::SinkExt,
     stream::{SplitStream, StreamExt},
 };
-use serde::{Deserialize, Serialize};
-use tokio::sync::mpsc;
-use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use tokio::sync::{Mutex, mpsc};
+use ts_rs::TS;
+use webrtc::{
+    ice_transport::ice_candidate::RTCIceCandidateInit, rtp_transceiver::rtp_codec::RTPCodecType,
+};
+
+pub const PROTOCOL_VERSION: u32 = 1;
+
+pub const CLOSE_UNSUPPORTED_VERSION: u16 = 4000;
+
+pub const CLOSE_RESUME_FAILED: u16 = 4001;
+
+pub const CLOSE_CONNECTION_TIMEOUT: u16 = 4002;
+
+pub const CLOSE_JOIN_TIMEOUT: u16 = 4003;
+
+pub const CLOSE_JOIN_REJECTED: u16 = 4004;
+
+pub const CLOSE_KICKED: u16 = 4005;
+
+pub const CLOSE_MEETING_ENDED: u16 = 4006;
+
+pub const CLOSE_DENIED: u16 = 4007;
+
+/// The client handles `endOfCandidates` messages.
+pub const FEATURE_END_OF_CANDIDATES: &str = "endOfCandidates";
+
+/// Wire encoding of a signaling socket, picked through `Sec-WebSocket-Protocol`.
+/// Clients that don't ask for a subprotocol get JSON.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Json,
+    MessagePack,
+}
+
+impl Encoding {
+    pub const PROTOCOLS: [&str; 2] = ["zoom.json", "zoom.msgpack"];
+
+    pub fn of(socket: &WebSocket) -> Self {
+        match socket.protocol().and_then(|p| p.to_str().ok()) {
+            Some("zoom.msgpack") => Encoding::MessagePack,
+            _ => Encoding::Json,
+        }
+    }
+
+    pub fn encode<T: Serialize>(self, value: &T) -> Result<WebSocketMessage> {
+        Ok(match self {
+            Encoding::Json => WebSocketMessage::Text(serde_json::to_string(value)?.into()),
+            Encoding::MessagePack => {
+                WebSocketMessage::Binary(rmp_serde::to_vec_named(value)?.into())
+            }
+        })
+    }
+}
+
+/// A data frame of any transport. Text frames hold JSON, binary frames MessagePack.
+#[derive(Clone, Copy, Debug)]
+pub enum Frame<'a> {
+    Text(&'a str),
+    Binary(&'a [u8]),
+}
+
+impl<'a> Frame<'a> {
+    /// Returns `None` for control frames.
+    pub fn of(message: &'a WebSocketMessage) -> Option<Self> {
+        match message {
+            WebSocketMessage::Text(text) => Some(Frame::Text(text)),
+            WebSocketMessage::Binary(bytes) => Some(Frame::Binary(bytes)),
+            _ => None,
+        }
+    }
+}
+
+/// Decodes a frame in whichever encoding its type implies, so both encodings are
+/// understood regardless of what was negotiated.
+pub fn decode<T: DeserializeOwned>(frame: Frame) -> Result<T> {
+    Ok(match frame {
+        Frame::Text(text) => serde_json::from_str(text)?,
+        Frame::Binary(bytes) => rmp_serde::from_slice(bytes)?,
+    })
+}
+
+/// Parses a client frame, checking it against the schema first if `validate` is set.
+pub fn parse(frame: Frame, validate: bool) -> Result<PeerEnvelope> {
+    if !validate {
+        return decode(frame);
+    }
+    let value = decode::<serde_json::Value>(frame)?;
+    schema::validate_peer_envelope(&value)?;
+    Ok(serde_json::from_value(value)?)
+}
+
+/// Schema stand-in for `RTCIceCandidateInit`, which comes from the webrtc crate.
+#[derive(JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct IceCandidateInit {
+    pub candidate: String,
+    pub sdp_mid: Option<String>,
+    #[serde(rename = "sdpMLineIndex")]
+    pub sdp_mline_index: Option<u16>,
+    pub username_fragment: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, TS, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum Handshake {
+    Hello { versions: Vec<u32> },
+    Welcome(u32),
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, TS, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerMessageId {
+    pub id: u32,
+    pub resume_token: String,
+    pub role: Role,
+    pub permissions: Permissions,
+}
+
+/// What a peer may do in its room. The first `/signal` peer in a room hosts it.
+#[derive(Serialize, Deserialize, JsonSchema, TS, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum Role {
+    Host,
+    CoHost,
+    Participant,
+    Viewer,
+}
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, TS, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Permissions {
+    pub publish_audio: bool,
+    pub publish_video: bool,
+    /// Publishing a second video track, which is taken for a screen share.
+    pub screen_share: bool,
+    pub chat: bool,
+    /// Changing the roles of others, kicking and muting them, running the waiting room,
+    /// breakout rooms and room settings, and ending the meeting.
+    pub moderate: bool,
+}
+
+impl Role {
+    pub fn permissions(self) -> Permissions {
+        let (publish, moderate) = match self {
+            Role::Host | Role::CoHost => (true, true),
+            Role::Participant => (true, false),
+            Role::Viewer => (false, false),
+        };
+        Permissions {
+            publish_audio: publish,
+            publish_video: publish,
+            screen_share: moderate,
+            chat: true,
+            moderate,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, TS, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct ServerMessagePeer {
     pub id: u32,
     pub name: String,
+    pub capabilities: Capabilities,
+    pub role: Role,
+    pub permissions: Permissions,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, TS, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub enum MediaKind {
+    Audio,
+    Video,
+}
+
+impl From<MediaKind> for RTPCodecType {
+    fn from(kind: MediaKind) -> Self {
+        match kind {
+            MediaKind::Audio => RTPCodecType::Audio,
+            MediaKind::Video => RTPCodecType::Video,
+        }
+    }
+}
+
+/// Who had which of a peer's tracks stopped or started.
+#[derive(Serialize, Deserialize, JsonSchema, TS, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerMessageMute {
+    pub peer: u32,
+    pub kind: MediaKind,
+    pub by: u32,
+}
+
+/// A join waiting in the room's lobby.
+#[derive(Serialize, Deserialize, JsonSchema, TS, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerMessageKnock {
+    pub id: u32,
+    pub name: String,
+    /// When it started waiting, in milliseconds since the Unix epoch.
+    #[ts(type = "number")]
+    pub since: u64,
+}
+
+/// The open breakout rooms, which peers are assigned to by index.
+#[derive(Serialize, Deserialize, JsonSchema, TS, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerMessageBreakouts {
+    pub names: Vec<String>,
+    /// Until they close and everyone returns to the main room.
+    pub seconds: u32,
+}
+
+/// What a room is about, set by the peer that opens it and changed by moderators.
+#[derive(Serialize, Deserialize, JsonSchema, TS, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomMetadata {
+    #[serde(default)]
+    #[ts(optional = nullable)]
+    pub title: Option<String>,
+    #[serde(default)]
+    #[ts(optional = nullable)]
+    pub description: Option<String>,
+    /// Anything else clients want to keep with the room. The server doesn't look inside.
+    #[serde(default)]
+    #[ts(type = "Record<string, unknown>")]
+    pub attributes: serde_json::Map<String, serde_json::Value>,
+}
+
+/// A room's metadata, with when and by whom the room was opened.
+#[derive(Serialize, Deserialize, JsonSchema, TS, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerMessageRoom {
+    #[serde(flatten)]
+    pub metadata: RoomMetadata,
+    /// When the room was opened, in milliseconds since the Unix epoch.
+    #[ts(type = "number")]
+    pub created: u64,
+    /// Name of the peer that opened the room, absent for breakout rooms.
+    pub creator: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, TS, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerMessageChat {
+    pub from: u32,
+    pub text: String,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, TS, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ErrorCode {
+    BadMessage,
+    BadSdp,
+    InvalidCandidate,
+    PermissionDenied,
+    RoomFull,
+    RoomLocked,
+    UnknownRoom,
+    NotStarted,
+    LimitExceeded,
+    Internal,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, TS, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerMessageError {
+    pub id: Option<u32>,
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, TS, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub enum ServerMessage {
-    Candidate(RTCIceCandidateInit),
+    Candidate(
+        #[schemars(with = "IceCandidateInit")]
+        #[ts(type = "RTCIceCandidateInit")]
+        RTCIceCandidateInit,
+    ),
+    /// The server finished gathering candidates for the ICE generation with this username fragment.
+    EndOfCandidates(Option<String>),
     Offer(String),
     Answer(String),
-    Id(u32),
+    Id(ServerMessageId),
+    Resumed(u32),
     Peers(Vec<ServerMessagePeer>),
     PeerJoined(ServerMessagePeer),
+    /// A peer's role changed, sent to the peer itself as well.
+    PeerUpdated(ServerMessagePeer),
     PeerLeft(u32),
+    Chat(ServerMessageChat),
+    /// The peer with this id removed you from the room. The connection closes next.
+    Kicked(u32),
+    /// A moderator stopped forwarding a peer's track to the room.
+    Muted(ServerMessageMute),
+    /// A moderator asks you to unmute.
+    UnmuteRequested(ServerMessageMute),
+    /// A peer lifted a moderator's mute of its track.
+    Unmuted(ServerMessageMute),
+    /// The peer with this id ended the meeting for everyone. The connection closes next.
+    MeetingEnded(u32),
+    /// Your join waits for a moderator to admit it.
+    Waiting,
+    /// Whether joins wait in the lobby, sent to moderators on joining and to everyone on changes.
+    WaitingRoom(bool),
+    /// Whether the room turns new joins away, sent to everyone on changes.
+    Locked(bool),
+    /// Sent to moderators.
+    LobbyJoined(ServerMessageKnock),
+    /// A join left the lobby, admitted, denied or gone. Sent to moderators.
+    LobbyLeft(u32),
+    /// Breakout rooms opened, sent to everyone in the meeting and to peers joining while
+    /// they are open.
+    Breakouts(ServerMessageBreakouts),
+    /// Seconds until the breakout rooms close, sent once a second near the end.
+    BreakoutCountdown(u32),
+    BreakoutsClosed,
+    /// You were moved to the breakout room with this index, or back to the main room.
+    /// The peers of the new room follow.
+    Moved(Option<u32>),
+    /// The room's metadata, sent on joining or moving to a room and whenever it changes.
+    Room(ServerMessageRoom),
+    Ack(u32),
+    Error(ServerMessageError),
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, TS, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerEnvelope {
+    pub id: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub re: Option<u32>,
+    pub message: ServerMessage,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, TS, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Capabilities {
+    /// Codec MIME types the client can decode, like `video/VP8`. Absent means all of them.
+    #[serde(default)]
+    #[ts(optional = nullable)]
+    pub codecs: Option<Vec<String>>,
+    /// How many video tracks the client can decode at once. Absent means no limit.
+    #[serde(default)]
+    #[ts(optional = nullable)]
+    pub max_video_decodes: Option<u32>,
+    /// Optional protocol features the client understands, see `FEATURE_*`.
+    #[serde(default)]
+    pub features: Vec<String>,
+}
+
+impl Capabilities {
+    pub fn decodes(&self, mime_type: &str) -> bool {
+        self.codecs.as_ref().is_none_or(|codecs| {
+            codecs
+                .iter()
+                .any(|codec| codec.eq_ignore_ascii_case(mime_type))
+        })
+    }
+
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+}
+
+/// First message of every new session; nothing else is accepted before it.
+#[derive(Serialize, Deserialize, JsonSchema, TS, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Join {
+    pub name: String,
+    #[serde(default)]
+    #[ts(optional)]
+    pub token: Option<String>,
+    pub client_version: String,
+    #[serde(default)]
+    pub capabilities: Capabilities,
+    /// Required to join a room whose code was reserved with a passcode.
+    #[serde(default)]
+    #[ts(optional)]
+    pub passcode: Option<String>,
+    /// Sets the metadata of a room that doesn't exist yet. Ignored otherwise.
+    #[serde(default)]
+    #[ts(optional)]
+    pub room: Option<RoomMetadata>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, TS, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct Mute {
+    pub peer: u32,
+    pub kind: MediaKind,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, TS, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct SetRole {
+    pub peer: u32,
+    pub role: Role,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, TS, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct OpenBreakouts {
+    pub names: Vec<String>,
+    /// How long they stay open.
+    pub seconds: u32,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, TS, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct AssignBreakout {
+    pub peer: u32,
+    /// Index of the breakout room, or none for the main room.
+    pub room: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, TS, Debug)]
 #[serde(rename_all = "camelCase")]
 pub enum PeerMessage {
-    Candidate(RTCIceCandidateInit),
+    Join(Join),
+    Candidate(
+        #[schemars(with = "IceCandidateInit")]
+        #[ts(type = "RTCIceCandidateInit")]
+        RTCIceCandidateInit,
+    ),
+    /// The client finished gathering candidates for the ICE generation with this username fragment.
+    EndOfCandidates(Option<String>),
+    /// Asks the server to restart ICE with a fresh offer, e.g. after a network change.
+    IceRestart,
     Offer(String),
     Answer(String),
-    Name(String),
     Pli(u32),
+    /// Needs the `moderate` permission. Nobody can be made host, or stop being one.
+    SetRole(SetRole),
+    /// Needs the `chat` permission.
+    Chat(String),
+    /// Removes a peer from the room. Needs the `moderate` permission, like the
+    /// other moderator actions, none of which work on the host.
+    Kick(u32),
+    /// Stops forwarding a peer's track until the peer unmutes it.
+    Mute(Mute),
+    AskUnmute(Mute),
+    /// Lifts a moderator's mute of one of your own tracks.
+    Unmute(MediaKind),
+    EndMeeting,
+    /// Turns the room's waiting room on or off. Turning it off admits everyone waiting.
+    SetWaitingRoom(bool),
+    /// Locks or unlocks the room. Locking turns away everyone in the lobby.
+    SetLocked(bool),
+    /// Lets a join from the lobby into the room.
+    Admit(u32),
+    /// Turns a join in the lobby away.
+    Deny(u32),
+    /// Opens breakout rooms off the main room. Needs the `moderate` permission, like the
+    /// other breakout actions, which only work from the main room.
+    OpenBreakouts(OpenBreakouts),
+    /// Moves a peer other than the host between the main and the breakout rooms.
+    AssignBreakout(AssignBreakout),
+    /// Spreads the participants in the main room evenly across the breakout rooms.
+    ShuffleBreakouts,
+    /// Closes the breakout rooms early, returning everyone to the main room.
+    CloseBreakouts,
+    /// Replaces the room's metadata. Needs the `moderate` permission.
+    SetRoom(RoomMetadata),
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, TS, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PeerEnvelope {
+    pub id: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub re: Option<u32>,
+    pub message: PeerMessage,
+}
+
+/// Waits for the client's hello and answers with the version both sides speak.
+/// Clients that don't offer a supported version get a close frame instead.
+pub async fn handshake(socket: &mut WebSocket) -> Result<bool> {
+    let hello = socket
+        .recv()
+        .await
+        .transpose()?
+        .and_then(|message| decode::<Handshake>(Frame::of(&message)?).ok());
+    if let Some(Handshake::Hello { versions }) = hello
+        && versions.contains(&PROTOCOL_VERSION)
+    {
+        let welcome = Encoding::of(socket).encode(&Handshake::Welcome(PROTOCOL_VERSION))?;
+        socket.send(welcome).await?;
+        return Ok(true);
+    }
+    close(
+        socket,
+        CLOSE_UNSUPPORTED_VERSION,
+        format!(
+            "unsupported protocol version, expected {}",
+            PROTOCOL_VERSION
+        ),
+    )
+    .await?;
+    Ok(false)
+}
+
+pub async fn close(socket: &mut WebSocket, code: CloseCode, reason: String) -> Result<()> {
+    socket
+        .send(WebSocketMessage::Close(Some(CloseFrame {
+            code,
+            reason: reason.into(),
+        })))
+        .await?;
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum Outgoing {
+    Envelope(ServerEnvelope),
+    Close(CloseCode, String),
+}
+
+/// Transport settings every connection of a session is started with.
+#[derive(Clone, Copy, Debug)]
+pub struct Settings {
+    pub ping_interval: Duration,
+    pub ping_timeout: Duration,
+    pub validate: bool,
+}
+
+/// Incoming half of a signaling connection.
+pub trait Receiver: Send + 'static {
+    /// Returns `None` once the connection is gone.
+    fn recv(&mut self) -> impl Future<Output = Result<Option<PeerEnvelope>>> + Send;
+
+    /// Whether the client closed the connection on purpose rather than it dropping.
+    fn closed(&self) -> bool;
+}
+
+/// A signaling connection, whatever carries it, that a session's `Sender` can be attached to.
+pub trait Connection: Send + Sized + 'static {
+    type Receiver: Receiver;
+
+    /// Starts delivering `backlog` and then everything arriving on `rx` to the client,
+    /// and returns the incoming half. Dropping `rx` must not fail the connection.
+    fn start(
+        self,
+        settings: Settings,
+        backlog: Vec<ServerEnvelope>,
+        rx: mpsc::Receiver<Outgoing>,
+    ) -> impl Future<Output = Result<Self::Receiver>> + Send;
+
+    /// Closes a connection that is never going to be started.
+    fn reject(self, code: CloseCode, reason: String) -> impl Future<Output = Result<()>> + Send;
+}
+
+struct Outbox {
+    next_id: u32,
+    history: VecDeque<ServerEnvelope>,
+    history_len: usize,
+    settings: Settings,
+    tx: Option<mpsc::Sender<Outgoing>>,
 }
 
+/// Outgoing side of a session. Messages are numbered and kept in a short history,
+/// so a connection attached after a reconnect can be caught up on what it missed.
 #[derive(Clone)]
 pub struct Sender {
-    tx: mpsc::Sender<ServerMessage>,
+    outbox: Arc<Mutex<Outbox>>,
 }
 
 impl Sender {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            outbox: Arc::new(Mutex::new(Outbox {
+                next_id: 1,
+                history: VecDeque::with_capacity(config.resume_history),
+                history_len: config.resume_history,
+                settings: Settings {
+                    ping_interval: config.ping_interval,
+                    ping_timeout: config.ping_timeout,
+                    validate: config.validate_messages,
+                },
+                tx: None,
+            })),
+        }
+    }
+
+    async fn send_envelope(&mut self, re: Option<u32>, message: ServerMessage) -> Result<u32> {
+        let mut outbox = self.outbox.lock().await;
+        let id = outbox.next_id;
+        outbox.next_id += 1;
+        let envelope = ServerEnvelope { id, re, message };
+        if outbox.history.len() == outbox.history_len {
+            outbox.history.pop_front();
+        }
+        if outbox.history_len > 0 {
+            outbox.history.push_back(envelope.clone());
+        }
+        if let Some(tx) = &outbox.tx
+            && tx.send(Outgoing::Envelope(envelope)).await.is_err()
+        {
+            outbox.tx = None;
+        }
+        Ok(id)
+    }
+
     pub async fn send(&mut self, message: ServerMessage) -> Result<()> {
-        Ok(self.tx.send(message).await?)
+        self.send_envelope(None, message).await?;
+        Ok(())
+    }
+
+    /// Sends a message the client is expected to reply to and returns its id.
+    pub async fn request(&mut self, message: ServerMessage) -> Result<u32> {
+        self.send_envelope(None, message).await
+    }
+
+    pub async fn reply(&mut self, re: u32, message: ServerMessage) -> Result<()> {
+        self.send_envelope(Some(re), message).await?;
+        Ok(())
+    }
+
+    pub async fn close(&mut self, code: CloseCode, reason: String) -> Result<()> {
+        if let Some(tx) = self.outbox.lock().await.tx.take() {
+            tx.send(Outgoing::Close(code, reason)).await?;
+        }
+        Ok(())
+    }
+
+    /// Makes the connection the destination of all further messages, after replaying
+    /// everything sent since message `last`. Replacing a previous connection stops its writer.
+    /// Returns `None`, rejecting the connection, if some of the missed messages are gone.
+    pub async fn attach<C: Connection>(&self, conn: C, last: u32) -> Result<Option<C::Receiver>> {
+        let mut outbox = self.outbox.lock().await;
+        let first = outbox.history.front().map_or(outbox.next_id, |e| e.id);
+        if first.saturating_sub(1) > last || last >= outbox.next_id {
+            conn.reject(CLOSE_RESUME_FAILED, "missed messages".into())
+                .await?;
+            return Ok(None);
+        }
+        let backlog = outbox
+            .history
+            .iter()
+            .filter(|e| e.id > last)
+            .cloned()
+            .collect();
+        let (tx, rx) = mpsc::channel(4);
+        let signal_rx = conn.start(outbox.settings, backlog, rx).await?;
+        outbox.tx = Some(tx);
+        Ok(Some(signal_rx))
     }
 }
 
-pub struct Receiver {
+impl Connection for WebSocket {
+    type Receiver = WebSocketReceiver;
+
+    async fn start(
+        self,
+        settings: Settings,
+        backlog: Vec<ServerEnvelope>,
+        mut rx: mpsc::Receiver<Outgoing>,
+    ) -> Result<WebSocketReceiver> {
+        let encoding = Encoding::of(&self);
+        let (mut sink, stream) = self.split();
+        for envelope in &backlog {
+            sink.send(encoding.encode(envelope)?).await?;
+        }
+        let mut ping = tokio::time::interval(settings.ping_interval);
+        tokio::spawn(async move {
+            loop {
+                let message = tokio::select! {
+                    outgoing = rx.recv() => match outgoing {
+                        Some(Outgoing::Envelope(envelope)) => match encoding.encode(&envelope) {
+                            Ok(message) => message,
+                            Err(e) => {
+                                println!("envelope {} not sent: {}", envelope.id, e);
+                                continue;
+                            }
+                        },
+                        Some(Outgoing::Close(code, reason)) => {
+                            WebSocketMessage::Close(Some(CloseFrame {
+                                code,
+                                reason: reason.into(),
+                            }))
+                        }
+                        None => break,
+                    },
+                    _ = ping.tick() => WebSocketMessage::Ping(Default::default()),
+                };
+                if sink.send(message).await.is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(WebSocketReceiver {
+            stream,
+            timeout: settings.ping_timeout,
+            validate: settings.validate,
+            closed: false,
+        })
+    }
+
+    async fn reject(mut self, code: CloseCode, reason: String) -> Result<()> {
+        close(&mut self, code, reason).await
+    }
+}
+
+pub struct WebSocketReceiver {
     stream: SplitStream<WebSocket>,
+    timeout: Duration,
+    validate: bool,
+    closed: bool,
 }
 
-impl Receiver {
-    pub async fn recv(&mut self) -> Result<Option<PeerMessage>> {
-        Ok(
-            if let Some(WebSocketMessage::Text(text)) = self
-                .stream
-                .next()
-                .await
-                .map_or(Ok(None), |res| res.map(Some))?
-            {
-                Some(serde_json::from_str::<PeerMessage>(&text)?)
-            } else {
-                None
-            },
-        )
-    }
-}
-
-pub fn channel(socket: WebSocket, error_tx: error::Sender) -> (Sender, Receiver) {
-    let (mut sink, stream) = socket.split();
-    let (tx, mut rx) = mpsc::channel(4);
-    error_tx.spawn(async move {
-        while let Some(message) = rx.recv().await {
-            sink.send(WebSocketMessage::Text(
-                serde_json::to_string(&message).unwrap().into(),
-            ))
-            .await?;
+impl Receiver for WebSocketReceiver {
+    /// Also returns `None` once the socket has been silent for longer than the ping
+    /// timeout, which is how half-open connections get noticed, or when it fails, as
+    /// it does when the network drops. Either way the session can still be resumed.
+    async fn recv(&mut self) -> Result<Option<PeerEnvelope>> {
+        while let Ok(next) = tokio::time::timeout(self.timeout, self.stream.next()).await {
+            let message = match next {
+                Some(Ok(message)) => message,
+                Some(Err(e)) => {
+                    println!("signaling socket failed: {}", e);
+                    break;
+                }
+                None => break,
+            };
+            if let WebSocketMessage::Close(_) = message {
+                self.closed = true;
+                break;
+            }
+            if let Some(frame) = Frame::of(&message) {
+                return Ok(Some(parse(frame, self.validate)?));
+            }
         }
-        Ok(())
-    });
-    (Sender { tx }, Receiver { stream })
+        Ok(None)
+    }
+
+    fn closed(&self) -> bool {
+        self.closed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::error::Error;
+
+    #[test]
+    fn envelopes_round_trip_in_both_encodings() {
+        let envelope = ServerEnvelope {
+            id: 3,
+            re: Some(2),
+            message: ServerMessage::Chat(ServerMessageChat {
+                from: 1,
+                text: "hi".into(),
+            }),
+        };
+        for encoding in [Encoding::Json, Encoding::MessagePack] {
+            let message = encoding.encode(&envelope).unwrap();
+            let frame = Frame::of(&message).unwrap();
+            assert_eq!(
+                matches!(frame, Frame::Binary(_)),
+                encoding == Encoding::MessagePack
+            );
+            let decoded: ServerEnvelope = decode(frame).unwrap();
+            assert_eq!((decoded.id, decoded.re), (3, Some(2)));
+            assert!(matches!(
+                decoded.message,
+                ServerMessage::Chat(ServerMessageChat { from: 1, text }) if text == "hi"
+            ));
+        }
+    }
+
+    #[test]
+    fn message_pack_frames_are_validated_like_json() {
+        let valid = rmp_serde::to_vec_named(&json!({"id": 1, "message": {"chat": "hi"}})).unwrap();
+        for validate in [false, true] {
+            let envelope = parse(Frame::Binary(&valid), validate).unwrap();
+            assert!(matches!(envelope.message, PeerMessage::Chat(text) if text == "hi"));
+        }
+        let invalid = rmp_serde::to_vec_named(&json!({"id": 1, "message": {"chat": 5}})).unwrap();
+        assert!(matches!(
+            parse(Frame::Binary(&invalid), true),
+            Err(Error::Schema { .. })
+        ));
+        assert!(parse(Frame::Binary(b"\xc1"), false).is_err());
+    }
 }
@@ -0,0 +1,268 @@
+use std::{collections::HashMap, convert::Infallible, net::SocketAddr, sync::Arc};
+
+use axum::{
+    body::Bytes,
+    extract::{self, ConnectInfo, Path, Query, ws::CloseCode},
+    http::{HeaderMap, StatusCode},
+    response::{
+        IntoResponse, Response,
+        sse::{Event, KeepAlive, Sse},
+    },
+};
+use serde_json::json;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    code,
+    error::{Error, Result},
+    server::{self, SignalParams},
+    signal::{
+        self, Connection, Frame, Handshake, Outgoing, PROTOCOL_VERSION, PeerEnvelope, Receiver,
+        ServerEnvelope, Settings,
+    },
+    state::State,
+    whip::has_content_type,
+};
+
+const MESSAGE_PACK: &str = "application/msgpack";
+
+pub enum Incoming {
+    Text(String),
+    Binary(Bytes),
+    Close,
+}
+
+/// Keeps a connection reachable for posts for as long as it lives.
+struct Registration {
+    state: Arc<State>,
+    id: String,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.state.remove_sse_connection(&self.id);
+    }
+}
+
+fn envelope_event(envelope: &ServerEnvelope) -> Result<Event> {
+    Ok(Event::default()
+        .id(envelope.id.to_string())
+        .json_data(envelope)?)
+}
+
+fn close_event(code: CloseCode, reason: String) -> Result<Event> {
+    Ok(Event::default()
+        .event("close")
+        .json_data(json!({"code": code, "reason": reason}))?)
+}
+
+/// Signaling over HTTP for clients that can't use WebSockets: server messages
+/// stream down as Server-Sent Events, client messages are posted one per request.
+pub struct SseConnection {
+    registration: Registration,
+    events: mpsc::Sender<Event>,
+    incoming: mpsc::Receiver<Incoming>,
+}
+
+impl Connection for SseConnection {
+    type Receiver = SseReceiver;
+
+    async fn start(
+        self,
+        settings: Settings,
+        backlog: Vec<ServerEnvelope>,
+        mut rx: mpsc::Receiver<Outgoing>,
+    ) -> Result<SseReceiver> {
+        let events = self.events;
+        for envelope in &backlog {
+            events.send(envelope_event(envelope)?).await.ok();
+        }
+        let (done_tx, done_rx) = oneshot::channel();
+        let (stop_tx, mut stop_rx) = oneshot::channel::<()>();
+        tokio::spawn(async move {
+            let _done = done_tx;
+            loop {
+                let event = tokio::select! {
+                    biased;
+                    outgoing = rx.recv() => match outgoing {
+                        Some(Outgoing::Envelope(envelope)) => match envelope_event(&envelope) {
+                            Ok(event) => event,
+                            Err(e) => {
+                                println!("envelope {} not sent: {}", envelope.id, e);
+                                continue;
+                            }
+                        },
+                        Some(Outgoing::Close(code, reason)) => {
+                            match close_event(code, reason) {
+                                Ok(event) => {
+                                    events.send(event).await.ok();
+                                }
+                                Err(e) => println!("close not sent: {}", e),
+                            }
+                            break;
+                        }
+                        None => break,
+                    },
+                    _ = events.closed() => break,
+                    // The stream ends with its receiver, so that a deleted
+                    // connection doesn't linger on keep-alives.
+                    _ = &mut stop_rx => break,
+                };
+                if events.send(event).await.is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(SseReceiver {
+            _registration: self.registration,
+            incoming: self.incoming,
+            done: done_rx,
+            _stop: stop_tx,
+            validate: settings.validate,
+            closed: false,
+        })
+    }
+
+    async fn reject(self, code: CloseCode, reason: String) -> Result<()> {
+        self.events.send(close_event(code, reason)?).await.ok();
+        Ok(())
+    }
+}
+
+pub struct SseReceiver {
+    _registration: Registration,
+    incoming: mpsc::Receiver<Incoming>,
+    done: oneshot::Receiver<()>,
+    _stop: oneshot::Sender<()>,
+    validate: bool,
+    closed: bool,
+}
+
+impl Receiver for SseReceiver {
+    /// Also returns `None` once the event stream ends, which is when its client is gone
+    /// or the server closed the connection.
+    async fn recv(&mut self) -> Result<Option<PeerEnvelope>> {
+        let incoming = tokio::select! {
+            incoming = self.incoming.recv() => incoming,
+            _ = &mut self.done => None,
+        };
+        match incoming {
+            Some(Incoming::Text(text)) => {
+                Ok(Some(signal::parse(Frame::Text(&text), self.validate)?))
+            }
+            Some(Incoming::Binary(bytes)) => {
+                Ok(Some(signal::parse(Frame::Binary(&bytes), self.validate)?))
+            }
+            Some(Incoming::Close) => {
+                self.closed = true;
+                Ok(None)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn closed(&self) -> bool {
+        self.closed
+    }
+}
+
+/// `GET /signal/sse`: takes the same parameters as `/signal`, plus the protocol
+/// versions the client speaks as `versions=1,2`, which stand in for its hello.
+/// The stream opens with an `endpoint` event naming the URL to post messages to and
+/// a `welcome` event, and ends with a `close` event when the server closes it.
+pub async fn events_handler(
+    extract::State(state): extract::State<Arc<State>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response {
+    let SignalParams { code, resume, last } = match SignalParams::parse(&params) {
+        Ok(params) => params,
+        Err(message) => return server::bad_request(message),
+    };
+    let supported = params.get("versions").is_some_and(|versions| {
+        versions
+            .split(',')
+            .any(|version| version.trim().parse() == Ok(PROTOCOL_VERSION))
+    });
+    if !supported {
+        return server::bad_request(&format!(
+            "unsupported protocol version, expected {}",
+            PROTOCOL_VERSION
+        ));
+    }
+
+    let welcome = match Event::default()
+        .event("welcome")
+        .json_data(Handshake::Welcome(PROTOCOL_VERSION))
+    {
+        Ok(welcome) => welcome,
+        Err(e) => return Error::from(e).into_response(),
+    };
+    let id = code::generate_token();
+    let (events, mut events_rx) = mpsc::channel(4);
+    let (incoming_tx, incoming) = mpsc::channel(4);
+    state.add_sse_connection(id.clone(), incoming_tx);
+    let endpoint = Event::default()
+        .event("endpoint")
+        .data(format!("/signal/sse/{}", id));
+    events.send(endpoint).await.ok();
+    events.send(welcome).await.ok();
+    let conn = SseConnection {
+        registration: Registration {
+            state: Arc::clone(&state),
+            id,
+        },
+        events,
+        incoming,
+    };
+    let keep_alive = KeepAlive::new().interval(state.config.ping_interval);
+    tokio::spawn(server::connect(state, conn, addr.ip(), code, resume, last));
+
+    let stream = futures::stream::poll_fn(move |cx| {
+        events_rx
+            .poll_recv(cx)
+            .map(|event| event.map(Ok::<_, Infallible>))
+    });
+    Sse::new(stream).keep_alive(keep_alive).into_response()
+}
+
+/// `POST /signal/sse/{connection}`: one `PeerEnvelope`, as JSON or, with an
+/// `application/msgpack` content type, as MessagePack.
+pub async fn post_handler(
+    extract::State(state): extract::State<Arc<State>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    if body.len() > state.config.limits.max_frame_size {
+        return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+    }
+    let Some(tx) = state.get_sse_connection(&id) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let incoming = if has_content_type(&headers, MESSAGE_PACK) {
+        Incoming::Binary(body)
+    } else {
+        match String::from_utf8(body.into()) {
+            Ok(text) => Incoming::Text(text),
+            Err(_) => return server::bad_request("message is not valid utf-8"),
+        }
+    };
+    match tx.send(incoming).await {
+        Ok(()) => StatusCode::ACCEPTED.into_response(),
+        Err(_) => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// `DELETE /signal/sse/{connection}`: the counterpart of closing the socket, which
+/// leaves the room right away instead of waiting for a resume.
+pub async fn delete_handler(
+    extract::State(state): extract::State<Arc<State>>,
+    Path(id): Path<String>,
+) -> Response {
+    let Some(tx) = state.get_sse_connection(&id) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    tx.send(Incoming::Close).await.ok();
+    StatusCode::NO_CONTENT.into_response()
+}
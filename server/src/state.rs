@@ -1,41 +1,432 @@
-use std::sync::{
-    Arc,
-    atomic::{AtomicU32, Ordering},
+use std::{
+    net::IpAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicU32, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
 use dashmap::{DashMap, Entry};
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, mpsc};
 
-use crate::room::Room;
+use crate::{
+    code,
+    config::Config,
+    error::{Error, Result, check_limit},
+    metrics::Metrics,
+    passcode::Passcode,
+    peer::Mode,
+    room::{Occupancy, Room},
+    schedule::Meeting,
+    session::Session,
+    sse,
+};
+
+/// A code that may open a room until it expires, and the passcode the room will have.
+struct Reservation {
+    expires: Instant,
+    passcode: Option<Arc<Passcode>>,
+}
 
 pub struct State {
+    pub config: Config,
+    pub metrics: Arc<Metrics>,
     next_peer_id: Arc<AtomicU32>,
     next_room_id: AtomicU32,
     rooms: DashMap<String, Arc<Mutex<Room>>>,
+    reservations: DashMap<String, Reservation>,
+    /// Scheduled meetings by code, until they end.
+    meetings: DashMap<String, Meeting>,
+    sessions: DashMap<String, Session>,
+    /// Open SSE connections by id, each taking the frames the client posts to it.
+    sse_connections: DashMap<String, mpsc::Sender<sse::Incoming>>,
+    publishers: AtomicUsize,
+    viewers: AtomicUsize,
+    /// Wrong passcodes per client, with the time of the last one.
+    passcode_failures: DashMap<IpAddr, (u32, Instant)>,
 }
 
 impl State {
-    pub fn new() -> Self {
+    pub fn new(config: Config) -> Self {
         Self {
+            config,
+            metrics: Arc::new(Metrics::default()),
             next_peer_id: Arc::new(AtomicU32::new(1)),
             next_room_id: AtomicU32::new(1),
             rooms: DashMap::new(),
+            reservations: DashMap::new(),
+            meetings: DashMap::new(),
+            sessions: DashMap::new(),
+            sse_connections: DashMap::new(),
+            publishers: AtomicUsize::new(0),
+            viewers: AtomicUsize::new(0),
+            passcode_failures: DashMap::new(),
+        }
+    }
+
+    /// Hands out a code that no room or reservation holds, reserved for a new room until
+    /// `ttl` passes. The room will take the passcode, which no join can change.
+    pub async fn reserve_room(&self, ttl: Duration, passcode: Option<String>) -> Result<String> {
+        let now = Instant::now();
+        self.reservations
+            .retain(|_, reservation| reservation.expires > now);
+        check_limit(
+            "reserved codes",
+            self.reservations.len() + 1,
+            self.config.max_reservations,
+        )?;
+        let passcode = match passcode {
+            Some(passcode) => {
+                let limit = self.config.limits.max_passcode_len;
+                check_limit("passcode", passcode.len(), limit)?;
+                Some(Arc::new(Passcode::hash(passcode).await))
+            }
+            None => None,
+        };
+        loop {
+            let code = code::generate();
+            if self.rooms.contains_key(&code) {
+                continue;
+            }
+            if let Entry::Vacant(entry) = self.reservations.entry(code.clone()) {
+                entry.insert(Reservation {
+                    expires: now + ttl,
+                    passcode,
+                });
+                return Ok(code);
+            }
         }
     }
 
-    pub fn get_room(&self, code: String) -> Arc<Mutex<Room>> {
+    /// The passcode of the room with this code, whether it is open or only reserved.
+    pub async fn room_passcode(&self, code: &str) -> Option<Arc<Passcode>> {
+        if let Some(room) = self.find_room(code) {
+            return room.lock().await.passcode.clone();
+        }
+        self.reservations
+            .get(code)
+            .and_then(|reservation| reservation.passcode.clone())
+    }
+
+    /// Returns the room with this code, opening it if the code is reserved.
+    pub fn open_room(&self, code: String) -> Result<Arc<Mutex<Room>>> {
         let id = self.next_room_id.fetch_add(1, Ordering::Relaxed);
         match self.rooms.entry(code) {
-            Entry::Occupied(entry) => Arc::clone(entry.get()),
+            Entry::Occupied(entry) => Ok(Arc::clone(entry.get())),
             Entry::Vacant(entry) => {
-                let room = Arc::new(Mutex::new(Room::new(id, Arc::clone(&self.next_peer_id))));
+                let meeting = self.get_meeting(entry.key());
+                if let Some(meeting) = &meeting
+                    && meeting.opens(self.config.early_join) > SystemTime::now()
+                {
+                    return Err(Error::NotStarted);
+                }
+                let reservation = self
+                    .reservations
+                    .remove(entry.key())
+                    .filter(|(_, reservation)| reservation.expires > Instant::now());
+                let Some((_, reservation)) = reservation else {
+                    return Err(Error::UnknownRoom);
+                };
+                let mut room = Room::new(
+                    id,
+                    Arc::clone(&self.next_peer_id),
+                    Arc::clone(&self.metrics),
+                    self.config.waiting_room,
+                );
+                // A scheduled meeting's room is titled after it until its opener says
+                // otherwise.
+                room.metadata.title = meeting.map(|meeting| meeting.title);
+                room.passcode = reservation.passcode;
+                let room = Arc::new(Mutex::new(room));
                 entry.insert(Arc::clone(&room));
-                room
+                Ok(room)
             }
         }
     }
 
-    pub fn remove_room(&self, code: &String) {
+    /// Opens a breakout room, titled by its name, which isn't found by code and can only
+    /// be moved into.
+    pub fn new_breakout_room(&self, index: u32, name: String) -> Arc<Mutex<Room>> {
+        let id = self.next_room_id.fetch_add(1, Ordering::Relaxed);
+        let mut room = Room::new(
+            id,
+            Arc::clone(&self.next_peer_id),
+            Arc::clone(&self.metrics),
+            false,
+        );
+        room.breakout = Some(index);
+        room.metadata.title = Some(name);
+        Arc::new(Mutex::new(room))
+    }
+
+    /// Like `open_room`, but doesn't open rooms that don't exist yet.
+    pub fn find_room(&self, code: &str) -> Option<Arc<Mutex<Room>>> {
+        self.rooms.get(code).map(|room| Arc::clone(&room))
+    }
+
+    /// Closes a room nobody is in, which the caller holds locked. Its code stays reserved
+    /// with its passcode, so that the meeting can go on until `reservation_ttl` passes, or
+    /// a scheduled meeting ends.
+    pub fn remove_room(&self, code: &str, room: &Room) {
         self.rooms.remove(code);
+        let mut ttl = self.config.reservation_ttl;
+        if let Some(meeting) = self.meetings.get(code)
+            && let Ok(left) = meeting.ends().duration_since(SystemTime::now())
+        {
+            ttl = ttl.max(left);
+        }
+        let reservation = Reservation {
+            expires: Instant::now() + ttl,
+            passcode: room.passcode.clone(),
+        };
+        self.reservations.insert(code.to_string(), reservation);
+    }
+
+    /// Adds a meeting scheduled for a reserved code, forgetting the ones that are over.
+    pub fn add_meeting(&self, meeting: Meeting) {
+        let now = SystemTime::now();
+        self.meetings.retain(|_, meeting| meeting.ends() > now);
+        self.meetings.insert(meeting.code.clone(), meeting);
+    }
+
+    pub fn get_meeting(&self, code: &str) -> Option<Meeting> {
+        self.meetings.get(code).map(|meeting| meeting.clone())
+    }
+
+    pub fn add_session(&self, token: String, session: Session) {
+        self.sessions.insert(token, session);
+    }
+
+    pub fn get_session(&self, token: &str) -> Option<Session> {
+        self.sessions.get(token).map(|s| s.clone())
+    }
+
+    /// Sessions of the peers in the room with this code.
+    pub fn room_sessions(&self, code: &str) -> Vec<Session> {
+        self.sessions
+            .iter()
+            .filter(|session| session.code() == code)
+            .map(|session| session.clone())
+            .collect()
+    }
+
+    pub fn remove_session(&self, token: &str) {
+        self.sessions.remove(token);
+    }
+
+    pub fn add_sse_connection(&self, id: String, tx: mpsc::Sender<sse::Incoming>) {
+        self.sse_connections.insert(id, tx);
+    }
+
+    pub fn get_sse_connection(&self, id: &str) -> Option<mpsc::Sender<sse::Incoming>> {
+        self.sse_connections.get(id).map(|tx| tx.clone())
+    }
+
+    pub fn remove_sse_connection(&self, id: &str) {
+        self.sse_connections.remove(id);
+    }
+
+    fn seats(&self, mode: Mode) -> (&AtomicUsize, Option<usize>) {
+        let capacity = &self.config.capacity;
+        if mode.publishes() {
+            (&self.publishers, capacity.publishers)
+        } else {
+            (&self.viewers, capacity.viewers)
+        }
+    }
+
+    /// Counts a new participant of this mode across all rooms, unless that would go
+    /// over the server's capacity. Every taken seat has to be released.
+    pub fn take_seat(&self, mode: Mode) -> bool {
+        let (count, limit) = self.seats(mode);
+        count
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                limit.is_none_or(|limit| n < limit).then_some(n + 1)
+            })
+            .is_ok()
+    }
+
+    pub fn release_seat(&self, mode: Mode) {
+        self.seats(mode).0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Participants across all rooms.
+    pub fn occupancy(&self) -> Occupancy {
+        Occupancy {
+            publishers: self.publishers.load(Ordering::Relaxed),
+            viewers: self.viewers.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Checks a join's passcode against a room's, counting wrong ones against the
+    /// client until it runs out of attempts.
+    pub async fn check_passcode(
+        &self,
+        passcode: Arc<Passcode>,
+        attempt: Option<&str>,
+        client: IpAddr,
+    ) -> Result<()> {
+        let lockout = self.config.passcode_lockout;
+        let max_attempts = self.config.max_passcode_attempts;
+        let locked_out = self
+            .passcode_failures
+            .get(&client)
+            .is_some_and(|failures| failures.0 >= max_attempts && failures.1.elapsed() < lockout);
+        if locked_out {
+            return Err(Error::Unauthorized("too many passcode attempts"));
+        }
+        let Some(attempt) = attempt else {
+            return Err(Error::Unauthorized("passcode required"));
+        };
+        if passcode.verify(attempt.to_string()).await {
+            self.passcode_failures.remove(&client);
+            return Ok(());
+        }
+        self.passcode_failures
+            .retain(|_, failures| failures.1.elapsed() < lockout);
+        let mut failures = self
+            .passcode_failures
+            .entry(client)
+            .or_insert((0, Instant::now()));
+        failures.0 += 1;
+        failures.1 = Instant::now();
+        Err(Error::Unauthorized("wrong passcode"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn only_reserved_codes_open_rooms() {
+        let state = State::new(Config::default());
+        assert!(matches!(
+            state.open_room("abc-def-ghi".into()),
+            Err(Error::UnknownRoom)
+        ));
+
+        let code = state
+            .reserve_room(Duration::from_secs(60), None)
+            .await
+            .unwrap();
+        assert!(code::is_valid(&code));
+        let room = state.open_room(code.clone()).unwrap();
+        let again = state.open_room(code.clone()).unwrap();
+        assert!(Arc::ptr_eq(&room, &again));
+
+        let expired = state.reserve_room(Duration::ZERO, None).await.unwrap();
+        assert!(matches!(state.open_room(expired), Err(Error::UnknownRoom)));
+    }
+
+    #[tokio::test]
+    async fn closed_room_code_stays_reserved_with_its_passcode() {
+        let state = State::new(Config::default());
+        let code = state
+            .reserve_room(Duration::from_secs(60), Some("secret".into()))
+            .await
+            .unwrap();
+        assert!(state.room_passcode(&code).await.is_some());
+        let room = state.open_room(code.clone()).unwrap();
+        assert!(room.lock().await.passcode.is_some());
+        state.remove_room(&code, &*room.lock().await);
+        assert!(state.find_room(&code).is_none());
+        assert!(state.room_passcode(&code).await.is_some());
+        let reopened = state.open_room(code).unwrap();
+        assert!(!Arc::ptr_eq(&room, &reopened));
+        assert!(reopened.lock().await.passcode.is_some());
+    }
+
+    #[tokio::test]
+    async fn reservations_are_bounded() {
+        let config = Config {
+            max_reservations: 2,
+            ..Config::default()
+        };
+        let state = State::new(config);
+        let ttl = Duration::from_secs(60);
+        state.reserve_room(ttl, None).await.unwrap();
+        state.reserve_room(Duration::ZERO, None).await.unwrap();
+        state.reserve_room(ttl, None).await.unwrap();
+        assert!(matches!(
+            state.reserve_room(ttl, None).await,
+            Err(Error::Limit { .. })
+        ));
+        let passcode = "x".repeat(state.config.limits.max_passcode_len + 1);
+        let state = State::new(Config::default());
+        assert!(matches!(
+            state.reserve_room(ttl, Some(passcode)).await,
+            Err(Error::Limit { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn wrong_passcodes_lock_the_client_out() {
+        let config = Config {
+            max_passcode_attempts: 2,
+            ..Config::default()
+        };
+        let state = State::new(config);
+        let passcode = Arc::new(Passcode::hash("secret".into()).await);
+        let client = IpAddr::from([192, 0, 2, 1]);
+        let other = IpAddr::from([192, 0, 2, 2]);
+
+        assert!(matches!(
+            state.check_passcode(passcode.clone(), None, client).await,
+            Err(Error::Unauthorized("passcode required"))
+        ));
+        state
+            .check_passcode(passcode.clone(), Some("secret"), client)
+            .await
+            .unwrap();
+        for _ in 0..2 {
+            assert!(matches!(
+                state
+                    .check_passcode(passcode.clone(), Some("guess"), client)
+                    .await,
+                Err(Error::Unauthorized("wrong passcode"))
+            ));
+        }
+        assert!(matches!(
+            state
+                .check_passcode(passcode.clone(), Some("secret"), client)
+                .await,
+            Err(Error::Unauthorized("too many passcode attempts"))
+        ));
+        state
+            .check_passcode(passcode.clone(), Some("secret"), other)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn passcode_lockout_expires() {
+        let config = Config {
+            max_passcode_attempts: 1,
+            passcode_lockout: Duration::from_millis(50),
+            ..Config::default()
+        };
+        let state = State::new(config);
+        let passcode = Arc::new(Passcode::hash("secret".into()).await);
+        let client = IpAddr::from([192, 0, 2, 1]);
+
+        assert!(
+            state
+                .check_passcode(passcode.clone(), Some("guess"), client)
+                .await
+                .is_err()
+        );
+        assert!(
+            state
+                .check_passcode(passcode.clone(), Some("secret"), client)
+                .await
+                .is_err()
+        );
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        state
+            .check_passcode(passcode.clone(), Some("secret"), client)
+            .await
+            .unwrap();
     }
 }
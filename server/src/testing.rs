@@ -0,0 +1,158 @@
+//! Helpers for tests that need peers in rooms.
+
+use std::{net::Ipv4Addr, sync::Arc, time::Duration};
+
+use axum::extract::ws::CloseCode;
+use tokio::{sync::mpsc, time::timeout};
+
+use crate::{
+    error,
+    peer::{Admission, Mode, Profile},
+    session::Session,
+    signal::{
+        self, Connection, Join, Outgoing, PeerEnvelope, PeerMessage, ServerEnvelope, ServerMessage,
+        Settings,
+    },
+    state::State,
+};
+
+pub fn join_message(name: &str) -> Join {
+    Join {
+        name: name.into(),
+        token: None,
+        client_version: "test".into(),
+        capabilities: Default::default(),
+        passcode: None,
+        room: None,
+    }
+}
+
+pub fn profile(name: &str) -> Profile {
+    Profile {
+        name: name.into(),
+        client_version: "test".into(),
+        capabilities: Default::default(),
+        authenticated: false,
+    }
+}
+
+/// Reserves a code for a room without a passcode.
+pub async fn reserve(state: &State) -> String {
+    let ttl = Duration::from_secs(60);
+    state.reserve_room(ttl, None).await.unwrap()
+}
+
+/// Joins the room with a signaling peer whose messages go nowhere.
+pub async fn join(state: &Arc<State>, code: &str, name: &str) -> Session {
+    try_join(state, code, join_message(name)).await.unwrap()
+}
+
+/// Like [`join`], but with the join message given and its failure returned.
+pub async fn try_join(state: &Arc<State>, code: &str, join: Join) -> error::Result<Session> {
+    let admission = Admission::from_join(join, Ipv4Addr::LOCALHOST.into(), &state.config)?;
+    let (error_tx, _error_rx) = error::channel();
+    let signal_tx = signal::Sender::new(&state.config);
+    Session::new(
+        Arc::clone(state),
+        code.into(),
+        Mode::Signal,
+        admission,
+        signal_tx,
+        error_tx,
+    )
+    .await
+}
+
+/// The envelope a client sends with a message.
+pub fn envelope(message: PeerMessage) -> PeerEnvelope {
+    PeerEnvelope {
+        id: 1,
+        re: None,
+        message,
+    }
+}
+
+/// A signaling connection that keeps everything the session sends, so that tests can
+/// check what a peer was told.
+struct TestConnection {
+    tx: mpsc::UnboundedSender<Outgoing>,
+}
+
+/// Takes nothing from the client: tests hand messages to the session directly.
+pub struct TestReceiver;
+
+impl signal::Receiver for TestReceiver {
+    async fn recv(&mut self) -> error::Result<Option<PeerEnvelope>> {
+        std::future::pending().await
+    }
+
+    fn closed(&self) -> bool {
+        false
+    }
+}
+
+impl Connection for TestConnection {
+    type Receiver = TestReceiver;
+
+    async fn start(
+        self,
+        _settings: Settings,
+        backlog: Vec<ServerEnvelope>,
+        mut rx: mpsc::Receiver<Outgoing>,
+    ) -> error::Result<TestReceiver> {
+        for envelope in backlog {
+            let _ = self.tx.send(Outgoing::Envelope(envelope));
+        }
+        // Drained as it comes, since a full outbox would block the session.
+        tokio::spawn(async move {
+            while let Some(outgoing) = rx.recv().await {
+                let _ = self.tx.send(outgoing);
+            }
+        });
+        Ok(TestReceiver)
+    }
+
+    async fn reject(self, _code: CloseCode, _reason: String) -> error::Result<()> {
+        Ok(())
+    }
+}
+
+/// What a session sent, from its first message on.
+pub struct Messages {
+    rx: mpsc::UnboundedReceiver<Outgoing>,
+    closed: Option<CloseCode>,
+}
+
+impl Messages {
+    /// The messages sent since the last call, once the session has been quiet for a
+    /// moment.
+    pub async fn take(&mut self) -> Vec<ServerMessage> {
+        let mut messages = Vec::new();
+        while let Ok(Some(outgoing)) = timeout(Duration::from_millis(20), self.rx.recv()).await {
+            match outgoing {
+                Outgoing::Envelope(envelope) => messages.push(envelope.message),
+                Outgoing::Close(code, _) => self.closed = Some(code),
+            }
+        }
+        messages
+    }
+
+    /// The code the connection was closed with, if it was.
+    pub async fn closed(&mut self) -> Option<CloseCode> {
+        self.take().await;
+        self.closed
+    }
+}
+
+/// Connects the session to a connection that keeps what it is sent.
+pub async fn connect(session: &Session) -> Messages {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let connection = TestConnection { tx };
+    session
+        .signal_tx()
+        .attach(connection, 0)
+        .await
+        .unwrap()
+        .unwrap();
+    Messages { rx, closed: None }
+}
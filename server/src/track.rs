@@ -1,4 +1,7 @@
-use std::sync::Arc;
+use std::sync::{
+    Arc,
+    atomic::{AtomicU32, Ordering},
+};
 
 use tokio::sync::mpsc;
 use webrtc::{
@@ -21,15 +24,24 @@ pub struct Sender {
     peer_id: u32,
     error_tx: error::Sender,
     tx: mpsc::Sender<Track>,
+    /// Video tracks the peer published so far. Any after the first is a screen share.
+    videos: Arc<AtomicU32>,
 }
 
 impl Sender {
     pub fn send(self, remote: Arc<TrackRemote>) {
         self.error_tx.spawn(async move {
             let kind = remote.kind();
+            let screen =
+                kind == RTPCodecType::Video && self.videos.fetch_add(1, Ordering::Relaxed) > 0;
+            let id = if screen {
+                format!("{}-screen", self.peer_id)
+            } else {
+                format!("{}-{}", self.peer_id, kind)
+            };
             let local = Arc::new(TrackLocalStaticRTP::new(
                 remote.codec().capability,
-                format!("{}-{}", self.peer_id.to_string(), kind),
+                id,
                 remote.stream_id(),
             ));
             self.tx
@@ -37,15 +49,16 @@ impl Sender {
                     inner: PeerTrack {
                         inner: Arc::clone(&local),
                         ssrc: remote.ssrc(),
+                        screen,
                     },
                     kind,
                 })
                 .await?;
             while let Ok((rtp, _)) = remote.read_rtp().await {
-                if let Err(e) = local.write_rtp(&rtp).await {
-                    if e != webrtc::Error::ErrClosedPipe {
-                        return Err(e.into());
-                    }
+                if let Err(e) = local.write_rtp(&rtp).await
+                    && e != webrtc::Error::ErrClosedPipe
+                {
+                    return Err(e.into());
                 }
             }
             Ok(())
@@ -70,6 +83,7 @@ pub fn channel(peer_id: u32, error_tx: error::Sender) -> (Sender, Receiver) {
             peer_id,
             tx,
             error_tx,
+            videos: Arc::default(),
         },
         Receiver { rx },
     )
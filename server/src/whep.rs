@@ -0,0 +1,62 @@
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+
+use axum::{
+    extract::{self, ConnectInfo, Path, Query},
+    http::HeaderMap,
+    response::Response,
+};
+
+use crate::{
+    peer::Mode,
+    server,
+    state::State,
+    whip::{Client, create_resource, delete_resource, get_session, patch_resource},
+};
+
+fn is_view(mode: Mode) -> bool {
+    matches!(mode, Mode::View(_))
+}
+
+/// `POST /whep/{code}`: answers with the room's tracks, or only those of the peer
+/// given as `?peer=`, which is only looked up once the join is let in. Tracks are
+/// matched to the offered media sections, so the offer decides how many of them the
+/// viewer gets.
+pub async fn post_handler(
+    extract::State(state): extract::State<Arc<State>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(code): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    offer: String,
+) -> Response {
+    let peer_id = match params.get("peer").map(|peer| peer.parse::<u32>()) {
+        None => None,
+        Some(Ok(peer_id)) => Some(peer_id),
+        Some(Err(_)) => return server::bad_request("invalid query parameter 'peer'"),
+    };
+    let client = Client {
+        addr: addr.ip(),
+        params,
+        headers,
+    };
+    create_resource(state, "whep", code, Mode::View(peer_id), client, offer).await
+}
+
+/// `PATCH /whep/{code}/{token}`
+pub async fn patch_handler(
+    extract::State(state): extract::State<Arc<State>>,
+    Path((code, token)): Path<(String, String)>,
+    headers: HeaderMap,
+    fragment: String,
+) -> Response {
+    let session = get_session(&state, &code, &token, is_view);
+    patch_resource(session, &headers, &fragment).await
+}
+
+/// `DELETE /whep/{code}/{token}`: stops watching and leaves the room.
+pub async fn delete_handler(
+    extract::State(state): extract::State<Arc<State>>,
+    Path((code, token)): Path<(String, String)>,
+) -> Response {
+    delete_resource(get_session(&state, &code, &token, is_view)).await
+}
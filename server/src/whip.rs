@@ -0,0 +1,275 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use axum::{
+    extract::{self, ConnectInfo, Path, Query},
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
+
+use crate::{
+    code, error,
+    peer::{Admission, Mode},
+    server::{self, serve_session},
+    session::Session,
+    signal::{self, Capabilities, Join},
+    state::State,
+};
+
+pub const SDP: &str = "application/sdp";
+
+pub const SDP_FRAGMENT: &str = "application/trickle-ice-sdpfrag";
+
+pub const PASSCODE_HEADER: &str = "x-room-passcode";
+
+pub fn has_content_type(headers: &HeaderMap, expected: &str) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|value| value.trim().eq_ignore_ascii_case(expected))
+}
+
+/// Looks up the session behind a WHIP or WHEP resource URL.
+pub fn get_session(
+    state: &State,
+    code: &str,
+    token: &str,
+    is_mode: impl Fn(Mode) -> bool,
+) -> Option<Session> {
+    state
+        .get_session(token)
+        .filter(|session| session.code() == code && is_mode(session.mode()))
+}
+
+/// The parts of a WHIP or WHEP `POST` that say who the client is.
+pub struct Client {
+    pub addr: IpAddr,
+    pub params: HashMap<String, String>,
+    pub headers: HeaderMap,
+}
+
+pub fn bearer_token(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string())
+}
+
+/// The room passcode an HTTP request carries in the `X-Room-Passcode` header, which
+/// keeps it out of URLs and their logs.
+pub fn passcode(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(PASSCODE_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+/// The join an HTTP client makes implicitly: its name comes from `?name=`, defaulting
+/// to the protocol's, its auth token from an `Authorization: Bearer` header and its
+/// passcode from `X-Room-Passcode`.
+fn http_join(protocol: &str, client: &Client) -> Join {
+    let params = &client.params;
+    Join {
+        name: params
+            .get("name")
+            .cloned()
+            .unwrap_or_else(|| protocol.to_uppercase()),
+        token: bearer_token(&client.headers),
+        client_version: protocol.into(),
+        capabilities: Capabilities::default(),
+        passcode: passcode(&client.headers),
+        room: None,
+    }
+}
+
+/// Joins the room with a peer negotiated by a single offer and answer, and responds
+/// with the answer and a resource URL under `/{protocol}`.
+pub async fn create_resource(
+    state: Arc<State>,
+    protocol: &str,
+    code: String,
+    mode: Mode,
+    client: Client,
+    offer: String,
+) -> Response {
+    if !code::is_valid(&code) {
+        return server::bad_request("invalid room code");
+    }
+    if !has_content_type(&client.headers, SDP) {
+        return StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response();
+    }
+    let join = http_join(protocol, &client);
+    let admission = match Admission::from_join(join, client.addr, &state.config) {
+        Ok(admission) => admission,
+        Err(e) => return e.into_response(),
+    };
+
+    let (error_tx, error_rx) = error::channel();
+    let signal_tx = signal::Sender::new(&state.config);
+    let session =
+        match Session::new(state, code.clone(), mode, admission, signal_tx, error_tx).await {
+            Ok(session) => session,
+            Err(e) => return e.into_response(),
+        };
+    serve_session(&session, error_rx).await;
+    let answer = match session.accept_offer(offer).await {
+        Ok(answer) => answer,
+        Err(e) => {
+            if let Err(e1) = session.leave().await {
+                println!("{}", e1);
+            }
+            return e.into_response();
+        }
+    };
+    (
+        StatusCode::CREATED,
+        [
+            (header::CONTENT_TYPE, SDP.to_string()),
+            (
+                header::LOCATION,
+                format!("/{}/{}/{}", protocol, code, session.token()),
+            ),
+        ],
+        answer,
+    )
+        .into_response()
+}
+
+/// Trickles the client's ICE candidates into the resource's peer.
+pub async fn patch_resource(
+    session: Option<Session>,
+    headers: &HeaderMap,
+    fragment: &str,
+) -> Response {
+    let Some(session) = session else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    if !has_content_type(headers, SDP_FRAGMENT) {
+        return StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response();
+    }
+    for candidate in parse_sdp_fragment(fragment) {
+        if let Err(e) = session.add_candidate(candidate).await {
+            return e.into_response();
+        }
+    }
+    StatusCode::NO_CONTENT.into_response()
+}
+
+/// Tears the resource's peer down through the normal leave path.
+pub async fn delete_resource(session: Option<Session>) -> Response {
+    let Some(session) = session else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    match session.leave().await {
+        Ok(()) => StatusCode::OK.into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+fn is_publish(mode: Mode) -> bool {
+    mode == Mode::Publish
+}
+
+/// `POST /whip/{code}`: publishes the offered tracks into the room, under the name
+/// given as `?name=`.
+pub async fn post_handler(
+    extract::State(state): extract::State<Arc<State>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(code): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    offer: String,
+) -> Response {
+    let client = Client {
+        addr: addr.ip(),
+        params,
+        headers,
+    };
+    create_resource(state, "whip", code, Mode::Publish, client, offer).await
+}
+
+/// `PATCH /whip/{code}/{token}`
+pub async fn patch_handler(
+    extract::State(state): extract::State<Arc<State>>,
+    Path((code, token)): Path<(String, String)>,
+    headers: HeaderMap,
+    fragment: String,
+) -> Response {
+    let session = get_session(&state, &code, &token, is_publish);
+    patch_resource(session, &headers, &fragment).await
+}
+
+/// `DELETE /whip/{code}/{token}`: stops publishing and leaves the room.
+pub async fn delete_handler(
+    extract::State(state): extract::State<Arc<State>>,
+    Path((code, token)): Path<(String, String)>,
+) -> Response {
+    delete_resource(get_session(&state, &code, &token, is_publish)).await
+}
+
+/// Turns a trickle ICE SDP fragment (RFC 8840) into candidates. An
+/// `a=end-of-candidates` line becomes the empty end-of-candidates candidate.
+fn parse_sdp_fragment(fragment: &str) -> Vec<RTCIceCandidateInit> {
+    let mut username_fragment = None;
+    let mut sdp_mid = None;
+    let mut candidates = Vec::new();
+    for line in fragment.lines().map(str::trim) {
+        if let Some(ufrag) = line.strip_prefix("a=ice-ufrag:") {
+            username_fragment = Some(ufrag.to_string());
+        } else if let Some(mid) = line.strip_prefix("a=mid:") {
+            sdp_mid = Some(mid.to_string());
+        } else if let Some(candidate) = line.strip_prefix("a=") {
+            let candidate = if candidate.starts_with("candidate:") {
+                candidate.to_string()
+            } else if candidate == "end-of-candidates" {
+                String::new()
+            } else {
+                continue;
+            };
+            candidates.push(RTCIceCandidateInit {
+                candidate,
+                sdp_mid: sdp_mid.clone(),
+                sdp_mline_index: None,
+                username_fragment: username_fragment.clone(),
+            });
+        }
+    }
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn token_and_passcode_come_from_their_own_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, "Bearer token".parse().unwrap());
+        headers.insert(PASSCODE_HEADER, "secret".parse().unwrap());
+        let client = Client {
+            addr: Ipv4Addr::LOCALHOST.into(),
+            params: HashMap::from([("passcode".into(), "from-url".into())]),
+            headers,
+        };
+        let join = http_join("whip", &client);
+        assert_eq!(join.name, "WHIP");
+        assert_eq!(join.token.as_deref(), Some("token"));
+        assert_eq!(join.passcode.as_deref(), Some("secret"));
+
+        let client = Client {
+            headers: HeaderMap::new(),
+            ..client
+        };
+        let join = http_join("whip", &client);
+        assert_eq!(join.token, None);
+        assert_eq!(join.passcode, None);
+    }
+}
@@ -0,0 +1,212 @@
+//! A signaling client speaking to a server started on a free port.
+
+#![allow(dead_code)]
+
+use std::{net::SocketAddr, time::Duration};
+
+use futures::{SinkExt, StreamExt};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tokio_tungstenite::{
+    MaybeTlsStream, WebSocketStream, connect_async,
+    tungstenite::{Message, protocol::frame::coding::CloseCode},
+};
+use zoom_server::{
+    config::Config,
+    server::Server,
+    signal::{
+        Capabilities, Handshake, Join, PROTOCOL_VERSION, PeerEnvelope, PeerMessage, ServerEnvelope,
+        ServerMessage,
+    },
+};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+pub async fn start(config: Config) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(Server::new("", config).serve(listener));
+    addr
+}
+
+/// A response read to its end.
+pub struct Response {
+    pub status: u16,
+    head: String,
+    pub body: String,
+}
+
+impl Response {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.head.lines().skip(1).find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            key.eq_ignore_ascii_case(name).then(|| value.trim())
+        })
+    }
+
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_str(&self.body).unwrap()
+    }
+}
+
+/// Sends an HTTP/1.0 request, which the server answers and then closes.
+pub async fn http(
+    addr: SocketAddr,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: &str,
+) -> Response {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let mut request = format!("{} {} HTTP/1.0\r\nHost: {}\r\n", method, path, addr);
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str(&format!("Content-Length: {}\r\n\r\n{}", body.len(), body));
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    tokio::time::timeout(TIMEOUT, stream.read_to_string(&mut response))
+        .await
+        .expect("timed out waiting for the server")
+        .unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+    Response {
+        status,
+        head: head.to_string(),
+        body: body.to_string(),
+    }
+}
+
+/// `GET /code`, answered with a freshly reserved room code.
+pub async fn reserve_code(addr: SocketAddr) -> String {
+    let response = http(addr, "GET", "/code", &[], "").await;
+    response.json()["code"].as_str().unwrap().to_string()
+}
+
+pub fn join(name: &str) -> PeerMessage {
+    PeerMessage::Join(Join {
+        name: name.into(),
+        token: None,
+        client_version: "test".into(),
+        capabilities: Capabilities::default(),
+        passcode: None,
+        room: None,
+    })
+}
+
+pub struct Client {
+    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    next_id: u32,
+    /// Id of the last envelope received, for resuming.
+    pub last: u32,
+}
+
+impl Client {
+    /// Connects to `/signal` with the query, without saying hello.
+    pub async fn open(addr: SocketAddr, query: &str) -> Self {
+        let url = format!("ws://{}/signal?{}", addr, query);
+        let (ws, _) = connect_async(url).await.unwrap();
+        Self {
+            ws,
+            next_id: 1,
+            last: 0,
+        }
+    }
+
+    /// Connects and completes the handshake.
+    pub async fn connect(addr: SocketAddr, query: &str) -> Self {
+        let mut client = Self::open(addr, query).await;
+        client
+            .send_json(&Handshake::Hello {
+                versions: vec![PROTOCOL_VERSION],
+            })
+            .await;
+        let welcome = client.recv_text().await.unwrap();
+        assert!(matches!(
+            serde_json::from_str(&welcome).unwrap(),
+            Handshake::Welcome(PROTOCOL_VERSION)
+        ));
+        client
+    }
+
+    pub async fn send_json<T: serde::Serialize>(&mut self, value: &T) {
+        let text = serde_json::to_string(value).unwrap();
+        self.ws.send(Message::text(text)).await.unwrap();
+    }
+
+    pub async fn send(&mut self, message: PeerMessage) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        let envelope = PeerEnvelope {
+            id,
+            re: None,
+            message,
+        };
+        self.send_json(&envelope).await;
+        id
+    }
+
+    /// The next text frame, or `None` once the connection closes.
+    async fn recv_text(&mut self) -> Option<String> {
+        loop {
+            let message = tokio::time::timeout(TIMEOUT, self.ws.next())
+                .await
+                .expect("timed out waiting for the server");
+            match message {
+                Some(Ok(Message::Text(text))) => return Some(text.to_string()),
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return None,
+                Some(Ok(_)) => {}
+            }
+        }
+    }
+
+    pub async fn recv(&mut self) -> ServerEnvelope {
+        let text = self.recv_text().await.expect("connection closed");
+        let envelope: ServerEnvelope = serde_json::from_str(&text).unwrap();
+        self.last = envelope.id;
+        envelope
+    }
+
+    /// Skips messages until one `f` picks something out of.
+    pub async fn recv_map<T>(&mut self, f: impl Fn(ServerMessage) -> Option<T>) -> T {
+        loop {
+            if let Some(value) = f(self.recv().await.message) {
+                return value;
+            }
+        }
+    }
+
+    /// Skips messages until the server closes the connection, and returns its close code.
+    pub async fn closed(&mut self) -> Option<u16> {
+        loop {
+            let message = tokio::time::timeout(TIMEOUT, self.ws.next())
+                .await
+                .expect("timed out waiting for the server to close");
+            match message {
+                Some(Ok(Message::Close(frame))) => {
+                    return frame.map(|frame| u16::from(frame.code));
+                }
+                Some(Err(_)) | None => return None,
+                Some(Ok(_)) => {}
+            }
+        }
+    }
+
+    /// Drops the connection without a close frame, as a network failure would.
+    pub fn drop_connection(self) {
+        drop(self.ws);
+    }
+
+    pub async fn close(mut self) {
+        self.ws
+            .close(Some(tokio_tungstenite::tungstenite::protocol::CloseFrame {
+                code: CloseCode::Normal,
+                reason: "".into(),
+            }))
+            .await
+            .ok();
+    }
+}
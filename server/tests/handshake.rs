@@ -0,0 +1,114 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{Client, join, reserve_code, start};
+use futures::{SinkExt, StreamExt};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{
+        self, Message,
+        client::IntoClientRequest,
+        http::{HeaderValue, header::SEC_WEBSOCKET_PROTOCOL},
+    },
+};
+use zoom_server::{
+    config::Config,
+    signal::{self, Handshake, PeerEnvelope, ServerEnvelope, ServerMessage},
+};
+
+#[tokio::test]
+async fn unsupported_version_is_closed() {
+    let addr = start(Config::default()).await;
+    let code = reserve_code(addr).await;
+    let mut client = Client::open(addr, &format!("code={}", code)).await;
+    client
+        .send_json(&Handshake::Hello {
+            versions: vec![signal::PROTOCOL_VERSION + 1],
+        })
+        .await;
+    assert_eq!(
+        client.closed().await,
+        Some(signal::CLOSE_UNSUPPORTED_VERSION)
+    );
+}
+
+#[tokio::test]
+async fn silent_connections_time_out() {
+    let config = Config {
+        join_timeout: Duration::from_millis(200),
+        ..Config::default()
+    };
+    let addr = start(config).await;
+    let code = reserve_code(addr).await;
+    let query = format!("code={}", code);
+
+    let mut no_hello = Client::open(addr, &query).await;
+    assert_eq!(no_hello.closed().await, Some(signal::CLOSE_JOIN_TIMEOUT));
+    let mut no_join = Client::connect(addr, &query).await;
+    assert_eq!(no_join.closed().await, Some(signal::CLOSE_JOIN_TIMEOUT));
+}
+
+#[tokio::test]
+async fn join_is_answered_with_id() {
+    let addr = start(Config::default()).await;
+    let code = reserve_code(addr).await;
+    let mut client = Client::connect(addr, &format!("code={}", code)).await;
+    client.send(join("host")).await;
+    let envelope = client.recv().await;
+    assert_eq!(envelope.id, 1);
+    let ServerMessage::Id(id) = envelope.message else {
+        panic!("expected id, got {:?}", envelope.message);
+    };
+    assert_eq!(id.role, signal::Role::Host);
+    assert!(id.permissions.moderate);
+}
+
+/// The next data frame, which has to be binary. Pings are skipped.
+async fn next_binary<S>(ws: &mut S) -> Vec<u8>
+where
+    S: StreamExt<Item = Result<Message, tungstenite::Error>> + Unpin,
+{
+    loop {
+        match ws.next().await {
+            Some(Ok(Message::Binary(bytes))) => return bytes.to_vec(),
+            Some(Ok(Message::Ping(_) | Message::Pong(_))) => {}
+            other => panic!("expected a binary frame, got {:?}", other),
+        }
+    }
+}
+
+#[tokio::test]
+async fn msgpack_subprotocol_uses_binary_frames() {
+    let addr = start(Config::default()).await;
+    let code = reserve_code(addr).await;
+    let url = format!("ws://{}/signal?code={}", addr, code);
+    let mut request = url.into_client_request().unwrap();
+    request.headers_mut().insert(
+        SEC_WEBSOCKET_PROTOCOL,
+        HeaderValue::from_static("zoom.msgpack"),
+    );
+    let (mut ws, response) = connect_async(request).await.unwrap();
+    assert_eq!(response.headers()[SEC_WEBSOCKET_PROTOCOL], "zoom.msgpack");
+
+    let hello = Handshake::Hello {
+        versions: vec![signal::PROTOCOL_VERSION],
+    };
+    let hello = rmp_serde::to_vec_named(&hello).unwrap();
+    ws.send(Message::binary(hello)).await.unwrap();
+    let welcome = next_binary(&mut ws).await;
+    assert!(matches!(
+        rmp_serde::from_slice(&welcome).unwrap(),
+        Handshake::Welcome(signal::PROTOCOL_VERSION)
+    ));
+
+    let envelope = PeerEnvelope {
+        id: 1,
+        re: None,
+        message: join("host"),
+    };
+    let envelope = rmp_serde::to_vec_named(&envelope).unwrap();
+    ws.send(Message::binary(envelope)).await.unwrap();
+    let envelope: ServerEnvelope = rmp_serde::from_slice(&next_binary(&mut ws).await).unwrap();
+    assert!(matches!(envelope.message, ServerMessage::Id(_)));
+}
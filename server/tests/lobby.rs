@@ -0,0 +1,170 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{Client, join, reserve_code, start};
+use tokio::sync::mpsc;
+use webrtc::{
+    api::{APIBuilder, media_engine::MediaEngine},
+    peer_connection::{
+        RTCPeerConnection, configuration::RTCConfiguration,
+        peer_connection_state::RTCPeerConnectionState,
+        sdp::session_description::RTCSessionDescription,
+    },
+    rtp_transceiver::rtp_codec::RTPCodecType,
+};
+use zoom_server::{
+    config::Config,
+    signal::{self, PeerMessage, ServerMessage},
+};
+
+fn waiting_room() -> Config {
+    Config {
+        waiting_room: true,
+        ..Config::default()
+    }
+}
+
+/// Joins as the host, then joins a guest that is put in the lobby. Returns both with
+/// the guest's lobby id.
+async fn knock(config: Config) -> (Client, Client, u32) {
+    let addr = start(config).await;
+    let code = reserve_code(addr).await;
+    let query = format!("code={}", code);
+    let mut host = Client::connect(addr, &query).await;
+    host.send(join("host")).await;
+    host.recv_map(|message| match message {
+        ServerMessage::Id(_) => Some(()),
+        _ => None,
+    })
+    .await;
+
+    let mut guest = Client::connect(addr, &query).await;
+    guest.send(join("guest")).await;
+    guest
+        .recv_map(|message| match message {
+            ServerMessage::Waiting => Some(()),
+            _ => None,
+        })
+        .await;
+    let knock = host
+        .recv_map(|message| match message {
+            ServerMessage::LobbyJoined(knock) => Some(knock),
+            _ => None,
+        })
+        .await;
+    assert_eq!(knock.name, "guest");
+    (host, guest, knock.id)
+}
+
+async fn new_peer_connection() -> RTCPeerConnection {
+    let mut engine = MediaEngine::default();
+    engine.register_default_codecs().unwrap();
+    let api = APIBuilder::new().with_media_engine(engine).build();
+    let conn = api
+        .new_peer_connection(RTCConfiguration::default())
+        .await
+        .unwrap();
+    conn.add_transceiver_from_kind(RTPCodecType::Audio, None)
+        .await
+        .unwrap();
+    conn
+}
+
+/// Offers once the join is admitted, as the web client does, and trickles candidates
+/// both ways until the peer connection is up.
+async fn negotiate(guest: &mut Client, conn: &RTCPeerConnection) {
+    let (candidate_tx, mut candidate_rx) = mpsc::unbounded_channel();
+    conn.on_ice_candidate(Box::new(move |candidate| {
+        if let Some(candidate) = candidate {
+            candidate_tx.send(candidate.to_json().unwrap()).ok();
+        }
+        Box::pin(async {})
+    }));
+    let (state_tx, mut state_rx) = mpsc::unbounded_channel();
+    conn.on_peer_connection_state_change(Box::new(move |state| {
+        state_tx.send(state).ok();
+        Box::pin(async {})
+    }));
+
+    let offer = conn.create_offer(None).await.unwrap();
+    conn.set_local_description(offer.clone()).await.unwrap();
+    guest.send(PeerMessage::Offer(offer.sdp)).await;
+    loop {
+        tokio::select! {
+            Some(candidate) = candidate_rx.recv() => {
+                guest.send(PeerMessage::Candidate(candidate)).await;
+            }
+            Some(state) = state_rx.recv() => {
+                assert_ne!(state, RTCPeerConnectionState::Failed);
+                if state == RTCPeerConnectionState::Connected {
+                    return;
+                }
+            }
+            envelope = guest.recv() => match envelope.message {
+                ServerMessage::Answer(sdp) => {
+                    let answer = RTCSessionDescription::answer(sdp).unwrap();
+                    conn.set_remote_description(answer).await.unwrap();
+                }
+                ServerMessage::Candidate(candidate) => {
+                    conn.add_ice_candidate(candidate).await.unwrap();
+                }
+                ServerMessage::Error(error) => panic!("server error: {:?}", error),
+                _ => {}
+            },
+        }
+    }
+}
+
+#[tokio::test]
+async fn admitted_join_connects() {
+    let (mut host, mut guest, knock) = knock(waiting_room()).await;
+    host.send(PeerMessage::Admit(knock)).await;
+    let id = guest
+        .recv_map(|message| match message {
+            ServerMessage::Id(id) => Some(id.id),
+            _ => None,
+        })
+        .await;
+
+    let conn = new_peer_connection().await;
+    tokio::time::timeout(Duration::from_secs(20), negotiate(&mut guest, &conn))
+        .await
+        .expect("peer connection not established");
+    let joined = host
+        .recv_map(|message| match message {
+            ServerMessage::PeerJoined(peer) => Some(peer.id),
+            _ => None,
+        })
+        .await;
+    assert_eq!(joined, id);
+    conn.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn denied_join_is_closed() {
+    let (mut host, mut guest, knock) = knock(waiting_room()).await;
+    host.send(PeerMessage::Deny(knock)).await;
+    assert_eq!(guest.closed().await, Some(signal::CLOSE_DENIED));
+    let left = host
+        .recv_map(|message| match message {
+            ServerMessage::LobbyLeft(id) => Some(id),
+            _ => None,
+        })
+        .await;
+    assert_eq!(left, knock);
+}
+
+#[tokio::test]
+async fn lobby_rejects_messages() {
+    let (_host, mut guest, _) = knock(waiting_room()).await;
+    let id = guest.send(PeerMessage::Chat("let me in".into())).await;
+    let error = guest
+        .recv_map(|message| match message {
+            ServerMessage::Error(error) => Some(error),
+            _ => None,
+        })
+        .await;
+    assert_eq!(error.id, Some(id));
+    assert_eq!(error.code, signal::ErrorCode::PermissionDenied);
+}
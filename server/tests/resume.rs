@@ -0,0 +1,71 @@
+mod common;
+
+use std::net::SocketAddr;
+
+use common::{Client, join, reserve_code, start};
+use zoom_server::{
+    config::Config,
+    signal::{self, ServerMessage},
+};
+
+/// Joins a room and returns the client after its `id`, with the peer id and resume token.
+async fn joined() -> (SocketAddr, String, Client, u32, String) {
+    let addr = start(Config::default()).await;
+    let code = reserve_code(addr).await;
+    let mut client = Client::connect(addr, &format!("code={}", code)).await;
+    client.send(join("host")).await;
+    let ServerMessage::Id(id) = client.recv().await.message else {
+        panic!("expected id");
+    };
+    (addr, code, client, id.id, id.resume_token)
+}
+
+#[tokio::test]
+async fn resume_replays_missed_messages() {
+    let (addr, code, client, peer_id, token) = joined().await;
+    // The room message that follows the id is never read.
+    let last = client.last;
+    client.drop_connection();
+
+    let query = format!("code={}&resume={}&last={}", code, token, last);
+    let mut client = Client::connect(addr, &query).await;
+    let replayed = client.recv().await;
+    assert_eq!(replayed.id, last + 1);
+    assert!(matches!(replayed.message, ServerMessage::Room(_)));
+    let resumed = client
+        .recv_map(|message| match message {
+            ServerMessage::Resumed(id) => Some(id),
+            _ => None,
+        })
+        .await;
+    assert_eq!(resumed, peer_id);
+
+    // The session is still usable after resuming.
+    let id = client.send(signal::PeerMessage::Chat("back".into())).await;
+    let acked = client
+        .recv_map(|message| match message {
+            ServerMessage::Ack(id) => Some(id),
+            _ => None,
+        })
+        .await;
+    assert_eq!(acked, id);
+}
+
+#[tokio::test]
+async fn resume_from_the_future_fails() {
+    let (addr, code, client, _, token) = joined().await;
+    let last = client.last + 100;
+    client.drop_connection();
+
+    let query = format!("code={}&resume={}&last={}", code, token, last);
+    let mut client = Client::connect(addr, &query).await;
+    assert_eq!(client.closed().await, Some(signal::CLOSE_RESUME_FAILED));
+}
+
+#[tokio::test]
+async fn unknown_resume_token_fails() {
+    let (addr, code, _client, _, _) = joined().await;
+    let query = format!("code={}&resume=unknown&last=0", code);
+    let mut client = Client::connect(addr, &query).await;
+    assert_eq!(client.closed().await, Some(signal::CLOSE_RESUME_FAILED));
+}
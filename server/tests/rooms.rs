@@ -0,0 +1,95 @@
+mod common;
+
+use std::{
+    collections::HashSet,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use common::{Client, http, start};
+use zoom_server::{
+    config::Config,
+    signal::{Join, PeerMessage, ServerMessage},
+};
+
+const AUTH: (&str, &str) = ("Authorization", "Bearer token");
+const PASSCODE: (&str, &str) = ("X-Room-Passcode", "secret");
+
+fn config() -> Config {
+    Config {
+        auth_tokens: HashSet::from(["token".to_string()]),
+        require_auth: true,
+        ..Config::default()
+    }
+}
+
+#[tokio::test]
+async fn room_metadata_takes_the_token_and_the_passcode() {
+    let addr = start(config()).await;
+    assert_eq!(http(addr, "GET", "/code", &[], "").await.status, 403);
+    let reserved = http(addr, "GET", "/code", &[AUTH, PASSCODE], "").await;
+    let code = reserved.json()["code"].as_str().unwrap().to_string();
+    let mut host = Client::connect(addr, &format!("code={}", code)).await;
+    host.send(PeerMessage::Join(Join {
+        name: "host".into(),
+        token: Some("token".into()),
+        client_version: "test".into(),
+        capabilities: Default::default(),
+        passcode: Some("secret".into()),
+        room: None,
+    }))
+    .await;
+    host.recv_map(|message| match message {
+        ServerMessage::Id(_) => Some(()),
+        _ => None,
+    })
+    .await;
+
+    let room = format!("/rooms/{}", code);
+    let occupancy = format!("/rooms/{}/occupancy", code);
+    for path in [&room, &occupancy] {
+        assert_eq!(http(addr, "GET", path, &[PASSCODE], "").await.status, 403);
+        assert_eq!(http(addr, "GET", path, &[AUTH], "").await.status, 403);
+        let wrong = ("X-Room-Passcode", "guess");
+        assert_eq!(
+            http(addr, "GET", path, &[AUTH, wrong], "").await.status,
+            403
+        );
+    }
+    let info = http(addr, "GET", &room, &[AUTH, PASSCODE], "").await;
+    assert_eq!(info.status, 200);
+    assert_eq!(info.json()["creator"], "host");
+    let occupied = http(addr, "GET", &occupancy, &[AUTH, PASSCODE], "").await;
+    assert_eq!(occupied.json()["publishers"], 1);
+}
+
+#[tokio::test]
+async fn meetings_are_read_with_the_token() {
+    let addr = start(config()).await;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let starts = now.as_millis() as u64 + 60_000;
+    let request = serde_json::json!({
+        "title": "Planning",
+        "starts": starts,
+        "ends": starts + 3_600_000,
+        "host": "host@example.com",
+    })
+    .to_string();
+    let json = ("Content-Type", "application/json");
+    assert_eq!(
+        http(addr, "POST", "/meetings", &[json], &request)
+            .await
+            .status,
+        403
+    );
+    let created = http(addr, "POST", "/meetings", &[json, AUTH], &request).await;
+    assert_eq!(created.status, 201);
+    let location = created.header("location").unwrap().to_string();
+
+    assert_eq!(http(addr, "GET", &location, &[], "").await.status, 403);
+    let ics = format!("{}/meeting.ics", location);
+    assert_eq!(http(addr, "GET", &ics, &[], "").await.status, 403);
+    let meeting = http(addr, "GET", &location, &[AUTH], "").await;
+    assert_eq!(meeting.status, 200);
+    assert_eq!(meeting.json()["title"], "Planning");
+    assert_eq!(http(addr, "GET", &ics, &[AUTH], "").await.status, 200);
+}
@@ -0,0 +1,126 @@
+mod common;
+
+use std::{net::SocketAddr, time::Duration};
+
+use common::{http, join, reserve_code, start};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    time::timeout,
+};
+use zoom_server::{
+    config::Config,
+    signal::{self, PeerEnvelope, ServerEnvelope, ServerMessage},
+};
+
+/// One server-sent event.
+#[derive(Debug, Default)]
+struct Event {
+    event: Option<String>,
+    id: Option<String>,
+    data: String,
+}
+
+/// The event stream of `GET /signal/sse`, read over HTTP/1.0 so that it comes
+/// unchunked.
+struct Events {
+    reader: BufReader<TcpStream>,
+}
+
+impl Events {
+    async fn open(addr: SocketAddr, query: &str) -> Self {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "GET /signal/sse?{} HTTP/1.0\r\nHost: {}\r\n\r\n",
+            query, addr
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut events = Self {
+            reader: BufReader::new(stream),
+        };
+        let status = events.line().await.unwrap();
+        assert!(status.contains(" 200 "), "{}", status);
+        while events.line().await.is_some_and(|line| !line.is_empty()) {}
+        events
+    }
+
+    async fn line(&mut self) -> Option<String> {
+        let mut line = String::new();
+        let read = timeout(Duration::from_secs(10), self.reader.read_line(&mut line))
+            .await
+            .expect("timed out waiting for the server")
+            .unwrap();
+        (read > 0).then(|| line.trim_end_matches(['\r', '\n']).to_string())
+    }
+
+    /// The next event other than a keep-alive, or `None` once the stream ends.
+    async fn next(&mut self) -> Option<Event> {
+        let mut event = Event::default();
+        let mut fields = false;
+        loop {
+            let line = self.line().await?;
+            if line.is_empty() {
+                if fields {
+                    return Some(event);
+                }
+                continue;
+            }
+            let (name, value) = line.split_once(':').unwrap_or((&line, ""));
+            let value = value.strip_prefix(' ').unwrap_or(value).to_string();
+            match name {
+                "event" => event.event = Some(value),
+                "id" => event.id = Some(value),
+                "data" => event.data = value,
+                _ => continue,
+            }
+            fields = true;
+        }
+    }
+}
+
+#[tokio::test]
+async fn messages_are_posted_and_streamed_back() {
+    let addr = start(Config::default()).await;
+    let code = reserve_code(addr).await;
+    let query = format!("code={}&versions={}", code, signal::PROTOCOL_VERSION);
+    let mut events = Events::open(addr, &query).await;
+    let endpoint = events.next().await.unwrap();
+    assert_eq!(endpoint.event.as_deref(), Some("endpoint"));
+    assert!(endpoint.data.starts_with("/signal/sse/"));
+    let welcome = events.next().await.unwrap();
+    assert_eq!(welcome.event.as_deref(), Some("welcome"));
+
+    let envelope = PeerEnvelope {
+        id: 1,
+        re: None,
+        message: join("host"),
+    };
+    let body = serde_json::to_string(&envelope).unwrap();
+    let posted = http(addr, "POST", &endpoint.data, &[], &body).await;
+    assert_eq!(posted.status, 202);
+    let id = events.next().await.unwrap();
+    assert_eq!(id.id.as_deref(), Some("1"));
+    let envelope: ServerEnvelope = serde_json::from_str(&id.data).unwrap();
+    assert!(matches!(envelope.message, ServerMessage::Id(_)));
+
+    let deleted = http(addr, "DELETE", &endpoint.data, &[], "").await;
+    assert_eq!(deleted.status, 204);
+    while events.next().await.is_some() {}
+    let gone = http(addr, "POST", &endpoint.data, &[], &body).await;
+    assert_eq!(gone.status, 404);
+    let occupancy = format!("/rooms/{}/occupancy", code);
+    let left = http(addr, "GET", &occupancy, &[], "").await;
+    assert_eq!(left.json()["publishers"], 0);
+}
+
+#[tokio::test]
+async fn unsupported_versions_are_refused() {
+    let addr = start(Config::default()).await;
+    let code = reserve_code(addr).await;
+    let query = format!("code={}&versions={}", code, signal::PROTOCOL_VERSION + 1);
+    let path = format!("/signal/sse?{}", query);
+    let refused = http(addr, "GET", &path, &[], "").await;
+    assert_eq!(refused.status, 400);
+    let unknown = http(addr, "POST", "/signal/sse/unknown", &[], "{}").await;
+    assert_eq!(unknown.status, 404);
+}
@@ -0,0 +1,134 @@
+mod common;
+
+use std::collections::HashSet;
+
+use common::{http, reserve_code, start};
+use webrtc::{
+    api::{APIBuilder, media_engine::MediaEngine},
+    peer_connection::configuration::RTCConfiguration,
+    rtp_transceiver::{
+        RTCRtpTransceiverInit, rtp_codec::RTPCodecType,
+        rtp_transceiver_direction::RTCRtpTransceiverDirection,
+    },
+};
+use zoom_server::config::Config;
+
+const SDP: (&str, &str) = ("Content-Type", "application/sdp");
+const SDP_FRAGMENT: (&str, &str) = ("Content-Type", "application/trickle-ice-sdpfrag");
+
+/// An offer with a single audio track going in `direction`.
+async fn offer(direction: RTCRtpTransceiverDirection) -> String {
+    let mut engine = MediaEngine::default();
+    engine.register_default_codecs().unwrap();
+    let api = APIBuilder::new().with_media_engine(engine).build();
+    let pc = api
+        .new_peer_connection(RTCConfiguration::default())
+        .await
+        .unwrap();
+    let init = RTCRtpTransceiverInit {
+        direction,
+        send_encodings: vec![],
+    };
+    pc.add_transceiver_from_kind(RTPCodecType::Audio, Some(init))
+        .await
+        .unwrap();
+    let offer = pc.create_offer(None).await.unwrap();
+    pc.close().await.unwrap();
+    offer.sdp
+}
+
+#[tokio::test]
+async fn published_resource_is_patched_and_deleted() {
+    let addr = start(Config::default()).await;
+    let code = reserve_code(addr).await;
+    let path = format!("/whip/{}?name=camera", code);
+    let offer = offer(RTCRtpTransceiverDirection::Sendonly).await;
+
+    let unsupported = http(addr, "POST", &path, &[], &offer).await;
+    assert_eq!(unsupported.status, 415);
+    let created = http(addr, "POST", &path, &[SDP], &offer).await;
+    assert_eq!(created.status, 201);
+    assert_eq!(created.header("content-type"), Some("application/sdp"));
+    assert!(created.body.starts_with("v=0"));
+    let location = created.header("location").unwrap().to_string();
+    assert!(location.starts_with(&format!("/whip/{}/", code)));
+    let occupancy = format!("/rooms/{}/occupancy", code);
+    let occupied = http(addr, "GET", &occupancy, &[], "").await;
+    assert_eq!(occupied.json()["publishers"], 1);
+
+    let fragment = "a=ice-ufrag:abcd\r\na=mid:0\r\na=end-of-candidates\r\n";
+    let patched = http(addr, "PATCH", &location, &[SDP_FRAGMENT], fragment).await;
+    assert_eq!(patched.status, 204);
+    let unsupported = http(addr, "PATCH", &location, &[SDP], fragment).await;
+    assert_eq!(unsupported.status, 415);
+    let unknown = format!("/whip/{}/unknown", code);
+    let missing = http(addr, "PATCH", &unknown, &[SDP_FRAGMENT], fragment).await;
+    assert_eq!(missing.status, 404);
+
+    assert_eq!(http(addr, "DELETE", &location, &[], "").await.status, 200);
+    assert_eq!(http(addr, "DELETE", &location, &[], "").await.status, 404);
+    let left = http(addr, "GET", &occupancy, &[], "").await;
+    assert_eq!(left.json()["publishers"], 0);
+}
+
+#[tokio::test]
+async fn publishing_needs_the_token_and_the_passcode() {
+    let config = Config {
+        auth_tokens: HashSet::from(["token".to_string()]),
+        require_auth: true,
+        ..Config::default()
+    };
+    let addr = start(config).await;
+    let auth = ("Authorization", "Bearer token");
+    let reserved = http(
+        addr,
+        "GET",
+        "/code",
+        &[auth, ("X-Room-Passcode", "secret")],
+        "",
+    )
+    .await;
+    let code = reserved.json()["code"].as_str().unwrap().to_string();
+    let path = format!("/whip/{}", code);
+    let offer = offer(RTCRtpTransceiverDirection::Sendonly).await;
+
+    let passcode = ("X-Room-Passcode", "secret");
+    let anonymous = http(addr, "POST", &path, &[SDP, passcode], &offer).await;
+    assert_eq!(anonymous.status, 403);
+    let no_passcode = http(addr, "POST", &path, &[SDP, auth], &offer).await;
+    assert_eq!(no_passcode.status, 403);
+    // The passcode doesn't go in the Authorization header.
+    let bearer_passcode = ("Authorization", "Bearer secret");
+    let misplaced = http(addr, "POST", &path, &[SDP, bearer_passcode], &offer).await;
+    assert_eq!(misplaced.status, 403);
+    let created = http(addr, "POST", &path, &[SDP, auth, passcode], &offer).await;
+    assert_eq!(created.status, 201);
+}
+
+#[tokio::test]
+async fn watched_peer_is_looked_up_after_admission() {
+    let config = Config {
+        auth_tokens: HashSet::from(["token".to_string()]),
+        require_auth: true,
+        ..Config::default()
+    };
+    let addr = start(config).await;
+    let auth = ("Authorization", "Bearer token");
+    let reserved = http(addr, "GET", "/code", &[auth], "").await;
+    let code = reserved.json()["code"].as_str().unwrap().to_string();
+    let offer = offer(RTCRtpTransceiverDirection::Recvonly).await;
+
+    // Without a token, nothing tells whether the room or the peer exists.
+    let path = format!("/whep/{}?peer=7", code);
+    let anonymous = http(addr, "POST", &path, &[SDP], &offer).await;
+    assert_eq!(anonymous.status, 403);
+    let unknown = http(addr, "POST", &path, &[SDP, auth], &offer).await;
+    assert_eq!(unknown.status, 400);
+    let everyone = format!("/whep/{}", code);
+    let watching = http(addr, "POST", &everyone, &[SDP, auth], &offer).await;
+    assert_eq!(watching.status, 201);
+    let location = watching.header("location").unwrap().to_string();
+    assert!(location.starts_with(&format!("/whep/{}/", code)));
+    let deleted = http(addr, "DELETE", &location, &[], "").await;
+    assert_eq!(deleted.status, 200);
+}
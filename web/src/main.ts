@@ -1,27 +1,29 @@
 import { Mutex } from "async-mutex";
+import {
+  CLOSE_CONNECTION_TIMEOUT,
+  CLOSE_DENIED,
+  CLOSE_JOIN_REJECTED,
+  CLOSE_JOIN_TIMEOUT,
+  CLOSE_KICKED,
+  CLOSE_MEETING_ENDED,
+  CLOSE_RESUME_FAILED,
+  CLOSE_UNSUPPORTED_VERSION,
+  FEATURE_END_OF_CANDIDATES,
+  PROTOCOL_VERSION,
+} from "./protocol/constants";
+import type {
+  Capabilities,
+  Handshake,
+  MediaKind,
+  PeerEnvelope,
+  PeerMessage,
+  ServerEnvelope,
+  ServerMessagePeer,
+} from "./protocol/signal";
 
-type ServerMessagePeer = {
-  id: number;
-  name: string;
-};
+const CLIENT_VERSION = "web/0.1.0";
 
-type ServerMessage =
-  | { candidate: RTCIceCandidateInit }
-  | { offer: string }
-  | { answer: string }
-  | { id: number }
-  | { peers: ServerMessagePeer[] }
-  | { peerJoined: ServerMessagePeer }
-  | { peerLeft: number };
-
-type PeerMessage =
-  | { candidate: RTCIceCandidateInit }
-  | { offer: string }
-  | { answer: string }
-  | { name: string }
-  | { pli: number };
-
-type MediaKind = "audio" | "video";
+const CLOSE_ABNORMAL = 1006;
 
 const app = document.getElementById("app")!;
 
@@ -34,7 +36,14 @@ function createElement<K extends keyof HTMLElementTagNameMap>(
   return el;
 }
 
-function createJoinForm(onSubmit: (name: string, code: string) => void) {
+function createJoinForm(
+  onSubmit: (
+    name: string,
+    code: string,
+    passcode: string,
+    title: string,
+  ) => void,
+) {
   const nameLabel = createElement("label", {
     htmlFor: "name",
     textContent: "Name",
@@ -56,23 +65,79 @@ function createJoinForm(onSubmit: (name: string, code: string) => void) {
     name: "code",
     required: true,
     placeholder: "abc-def-ghi",
+    value: new URLSearchParams(location.search).get("code") ?? "",
+  });
+  const newCodeButton = createElement("button", {
+    type: "button",
+    textContent: "New meeting",
+  });
+  newCodeButton.addEventListener("click", async () => {
+    // A new meeting takes the passcode that is filled in, if any.
+    const headers: Record<string, string> = passcodeInput.value
+      ? { "X-Room-Passcode": passcodeInput.value }
+      : {};
+    const response = await fetch("http://localhost:3000/code", { headers });
+    const { code } = await response.json();
+    codeInput.value = code;
+  });
+  const passcodeLabel = createElement("label", {
+    htmlFor: "passcode",
+    textContent: "Passcode",
+  });
+  const passcodeInput = createElement("input", {
+    type: "password",
+    id: "passcode",
+    name: "passcode",
+    placeholder: "optional",
+  });
+  const titleLabel = createElement("label", {
+    htmlFor: "title",
+    textContent: "Title",
+  });
+  const titleInput = createElement("input", {
+    type: "text",
+    id: "title",
+    name: "title",
+    placeholder: "optional, for new meetings",
   });
   const submitButton = createElement("button", {
     type: "submit",
     textContent: "Join",
   });
   const joinForm = createElement("form", {});
-  joinForm.append(nameLabel, nameInput, codeLabel, codeInput, submitButton);
+  joinForm.append(
+    nameLabel,
+    nameInput,
+    codeLabel,
+    codeInput,
+    newCodeButton,
+    passcodeLabel,
+    passcodeInput,
+    titleLabel,
+    titleInput,
+    submitButton,
+  );
   joinForm.addEventListener("submit", (event) => {
     event.preventDefault();
-    onSubmit(nameInput.value, codeInput.value);
+    onSubmit(
+      nameInput.value,
+      codeInput.value,
+      passcodeInput.value,
+      titleInput.value,
+    );
   });
   return joinForm;
 }
 
 const joinForm = createJoinForm(join);
+const roomTitle = createElement("h2", {});
 const mediaContainer = createElement("div", {});
 const leaveButton = createElement("button", { textContent: "Leave" });
+const lockButton = createElement("button", { textContent: "Lock", hidden: true });
+const breakoutButton = createElement("button", {
+  textContent: "Breakout rooms",
+  hidden: true,
+});
 app.append(joinForm);
 
 function addMedia(
@@ -106,25 +171,72 @@ function removeMediaAndName(id: number) {
   }
 }
 
+function showModeratorControls(moderate: boolean) {
+  lockButton.hidden = !moderate;
+  breakoutButton.hidden = !moderate;
+}
+
+function getCapabilities(): Capabilities {
+  const codecs = (["audio", "video"] as const).flatMap(
+    (kind) =>
+      RTCRtpReceiver.getCapabilities(kind)?.codecs.map(
+        (codec) => codec.mimeType,
+      ) ?? [],
+  );
+  return {
+    codecs: [...new Set(codecs)],
+    maxVideoDecodes: null,
+    features: [FEATURE_END_OF_CANDIDATES],
+  };
+}
+
 function getPeerIdFromTrackId(id: string) {
   return parseInt(id.split(" ")[0]);
 }
 
-async function join(name: string, code: string) {
+async function join(
+  name: string,
+  code: string,
+  passcode: string,
+  title: string,
+) {
   app.removeChild(joinForm);
-  app.append(leaveButton, mediaContainer);
+  app.append(
+    roomTitle,
+    leaveButton,
+    lockButton,
+    breakoutButton,
+    mediaContainer,
+  );
   const stream = await navigator.mediaDevices.getUserMedia({
     audio: true,
     video: true,
   });
   const mutex = new Mutex();
   const rtc = new RTCPeerConnection();
-  const signal = new WebSocket(`http://localhost:3000/signal?code=${code}`);
+  let sendSignal: (data: string) => void;
+  let closeSignal: () => void;
+  let signalOpen = false;
+  let useSse = false;
   let id: number | null = null;
+  let resumeToken: string | null = null;
+  let lastMessageId = 0;
   let peers: ServerMessagePeer[] = [];
   let pendingCandidates: RTCIceCandidateInit[] = [];
-  function send(message: PeerMessage) {
-    signal.send(JSON.stringify(message));
+  let nextMessageId = 1;
+  let started = false;
+  let leaving = false;
+  let locked = false;
+  let breakoutsOpen = false;
+  function send(message: PeerMessage, re?: number) {
+    const messageId = nextMessageId++;
+    const envelope: PeerEnvelope = { id: messageId, re, message };
+    sendSignal(JSON.stringify(envelope));
+    return messageId;
+  }
+  function localIceUfrag() {
+    const match = rtc.localDescription?.sdp.match(/^a=ice-ufrag:(.*)$/m);
+    return match ? match[1].trim() : null;
   }
   async function setRemoteDescription(description: RTCSessionDescriptionInit) {
     await rtc.setRemoteDescription(description);
@@ -134,21 +246,56 @@ async function join(name: string, code: string) {
     pendingCandidates = [];
   }
   leaveButton.addEventListener("click", () => {
-    signal.close();
+    leaving = true;
+    closeSignal();
   });
+  lockButton.onclick = () => send({ setLocked: !locked });
+  breakoutButton.onclick = () => {
+    if (breakoutsOpen) {
+      send("closeBreakouts");
+      return;
+    }
+    const names = prompt("Breakout room names, separated by commas", "1, 2");
+    const minutes = Number(prompt("How many minutes?", "10"));
+    if (!names || !minutes) {
+      return;
+    }
+    send({
+      openBreakouts: {
+        names: names.split(",").map((name) => name.trim()),
+        seconds: Math.round(minutes * 60),
+      },
+    });
+    send("shuffleBreakouts");
+  };
+  function restartIce() {
+    if (started && signalOpen) {
+      console.log("requesting ice restart");
+      send("iceRestart");
+    }
+  }
   rtc.addEventListener("connectionstatechange", () => {
     if (rtc.connectionState === "connected") {
       console.log("connection established");
+    } else if (
+      rtc.connectionState === "disconnected" ||
+      rtc.connectionState === "failed"
+    ) {
+      restartIce();
     }
   });
+  window.addEventListener("online", restartIce);
   rtc.addEventListener("icecandidate", (event) => {
-    console.log("new local ice candidate");
     if (event.candidate) {
+      console.log("new local ice candidate");
       const { candidate, sdpMid, sdpMLineIndex, usernameFragment } =
         event.candidate;
       send({
         candidate: { candidate, sdpMid, sdpMLineIndex, usernameFragment },
       });
+    } else {
+      console.log("local ice gathering complete");
+      send({ endOfCandidates: localIceUfrag() });
     }
   });
   rtc.addEventListener("track", (event) => {
@@ -161,7 +308,8 @@ async function join(name: string, code: string) {
       addName(peer.name, peerId);
     }
   });
-  signal.addEventListener("open", async () => {
+  async function start() {
+    started = true;
     setInterval(async () => {
       const stats = await rtc.getStats();
       stats.forEach((report: RTCInboundRtpStreamStats) => {
@@ -175,19 +323,38 @@ async function join(name: string, code: string) {
       });
     }, 100);
     stream.getTracks().forEach((track) => rtc.addTrack(track, stream));
-    send({ name });
-    console.log("name sent");
-    await mutex.runExclusive(async () => {
-      const offer = await rtc.createOffer();
-      await rtc.setLocalDescription(offer);
-      send({ offer: offer.sdp! });
-      console.log("offer sent");
-      console.log(offer.sdp);
+    send({
+      join: {
+        name,
+        clientVersion: CLIENT_VERSION,
+        capabilities: getCapabilities(),
+        passcode: passcode || undefined,
+        room: title ? { title, attributes: {} } : undefined,
+      },
     });
-  });
-  signal.addEventListener("message", async (event) => {
-    const message = JSON.parse(event.data) as ServerMessage;
-    if ("candidate" in message) {
+    console.log("join sent");
+  }
+  async function sendOffer() {
+    const offer = await rtc.createOffer();
+    await rtc.setLocalDescription(offer);
+    send({ offer: offer.sdp! });
+    console.log("offer sent");
+    console.log(offer.sdp);
+  }
+  async function handleMessage(envelope: ServerEnvelope) {
+    lastMessageId = envelope.id;
+    const message = envelope.message;
+    if (message === "waiting") {
+      console.log("waiting for a moderator to let you in");
+    } else if (message === "breakoutsClosed") {
+      console.log("breakout rooms closed");
+      breakoutsOpen = false;
+      breakoutButton.textContent = "Breakout rooms";
+    } else if ("ack" in message) {
+      console.log("message acknowledged", message.ack);
+    } else if ("error" in message) {
+      console.error("server error", message.error);
+    } else if ("candidate" in message) {
       console.log("new remote ice candidate");
       await mutex.runExclusive(async () => {
         if (rtc.remoteDescription) {
@@ -196,20 +363,37 @@ async function join(name: string, code: string) {
           pendingCandidates.push(message.candidate);
         }
       });
+    } else if ("endOfCandidates" in message) {
+      console.log("remote ice gathering complete");
+      const candidate: RTCIceCandidateInit = {
+        candidate: "",
+        usernameFragment: message.endOfCandidates,
+      };
+      await mutex.runExclusive(async () => {
+        if (rtc.remoteDescription) {
+          await rtc.addIceCandidate(candidate);
+        } else {
+          pendingCandidates.push(candidate);
+        }
+      });
     } else if ("offer" in message) {
       await mutex.runExclusive(async () => {
         console.log("offer received");
         console.log(message.offer);
-        if (rtc.signalingState === "have-local-offer") {
+        const collision = rtc.signalingState === "have-local-offer";
+        if (collision) {
           console.log("rollback");
           await rtc.setLocalDescription({ type: "rollback" });
         }
         await setRemoteDescription({ type: "offer", sdp: message.offer });
         const answer = await rtc.createAnswer();
         await rtc.setLocalDescription(answer);
-        send({ answer: answer.sdp! });
+        send({ answer: answer.sdp! }, envelope.id);
         console.log("answer sent");
         console.log(answer.sdp);
+        if (collision) {
+          await sendOffer();
+        }
       });
     } else if ("answer" in message) {
       await mutex.runExclusive(async () => {
@@ -221,10 +405,17 @@ async function join(name: string, code: string) {
       console.log("id received");
       console.log(message.id);
       if (!id) {
-        id = message.id;
+        id = message.id.id;
+        resumeToken = message.id.resumeToken;
+        showModeratorControls(message.id.permissions.moderate);
         addMedia(stream, "video", id, true);
         addName(name, id);
+        // There is no peer to take the offer until the join is admitted, which may
+        // take a moderator when it waits in the lobby.
+        await mutex.runExclusive(sendOffer);
       }
+    } else if ("resumed" in message) {
+      console.log("session resumed");
     } else if ("peers" in message) {
       console.log("peers received");
       console.log(message.peers);
@@ -233,20 +424,194 @@ async function join(name: string, code: string) {
       console.log("peer joined");
       console.log(message.peerJoined);
       peers.push(message.peerJoined);
+    } else if ("peerUpdated" in message) {
+      console.log("peer updated");
+      console.log(message.peerUpdated);
+      const updated = message.peerUpdated;
+      peers = peers.map((peer) => (peer.id === updated.id ? updated : peer));
+      if (updated.id === id) {
+        showModeratorControls(updated.permissions.moderate);
+      }
+    } else if ("chat" in message) {
+      console.log("chat from", message.chat.from, message.chat.text);
+    } else if ("muted" in message) {
+      console.log("muted", message.muted);
+    } else if ("unmuted" in message) {
+      console.log("unmuted", message.unmuted);
+    } else if ("unmuteRequested" in message) {
+      const { kind } = message.unmuteRequested;
+      if (confirm(`The host asks you to turn your ${kind} back on.`)) {
+        send({ unmute: kind });
+      }
+    } else if ("kicked" in message) {
+      console.log("removed from the room by", message.kicked);
+    } else if ("meetingEnded" in message) {
+      console.log("meeting ended by", message.meetingEnded);
+    } else if ("waitingRoom" in message) {
+      console.log("waiting room", message.waitingRoom ? "on" : "off");
+    } else if ("locked" in message) {
+      locked = message.locked;
+      console.log("room", locked ? "locked" : "unlocked");
+      lockButton.textContent = locked ? "Unlock" : "Lock";
+    } else if ("breakouts" in message) {
+      console.log("breakout rooms opened", message.breakouts);
+      breakoutsOpen = true;
+      breakoutButton.textContent = "Close breakout rooms";
+    } else if ("breakoutCountdown" in message) {
+      console.log("breakout rooms close in", message.breakoutCountdown);
+    } else if ("moved" in message) {
+      console.log("moved to", message.moved ?? "the main room");
+      for (const peer of peers) {
+        removeMediaAndName(peer.id);
+      }
+      peers = [];
+    } else if ("room" in message) {
+      console.log("room", message.room);
+      roomTitle.textContent = message.room.title ?? code;
+      document.title = roomTitle.textContent;
+    } else if ("lobbyJoined" in message) {
+      const knock = message.lobbyJoined;
+      if (confirm(`${knock.name} is waiting to join. Let them in?`)) {
+        send({ admit: knock.id });
+      } else {
+        send({ deny: knock.id });
+      }
+    } else if ("lobbyLeft" in message) {
+      console.log("left the lobby", message.lobbyLeft);
     } else if ("peerLeft" in message) {
       console.log("peer left");
       console.log(message.peerLeft);
       peers = peers.filter((peer) => peer.id !== message.peerLeft);
       removeMediaAndName(message.peerLeft);
     }
-  });
-  signal.addEventListener("close", () => {
-    console.log("connection closed");
+  }
+  function close() {
     rtc.close();
     stream.getTracks().forEach((track) => track.stop());
     app.removeChild(leaveButton);
+    app.removeChild(lockButton);
+    app.removeChild(breakoutButton);
+    showModeratorControls(false);
+    lockButton.textContent = "Lock";
+    breakoutButton.textContent = "Breakout rooms";
     app.removeChild(mediaContainer);
     mediaContainer.innerHTML = "";
     app.appendChild(joinForm);
-  });
+  }
+  async function handleWelcome(handshake: Handshake) {
+    if ("welcome" in handshake) {
+      console.log("protocol version", handshake.welcome);
+      signalOpen = true;
+      if (!started) {
+        await start();
+      }
+    }
+  }
+  function handleClose(code: number, reason: string) {
+    console.log("connection closed", code, reason);
+    signalOpen = false;
+    if (
+      leaving ||
+      !resumeToken ||
+      code === CLOSE_UNSUPPORTED_VERSION ||
+      code === CLOSE_RESUME_FAILED ||
+      code === CLOSE_CONNECTION_TIMEOUT ||
+      code === CLOSE_JOIN_TIMEOUT ||
+      code === CLOSE_JOIN_REJECTED ||
+      code === CLOSE_KICKED ||
+      code === CLOSE_MEETING_ENDED ||
+      code === CLOSE_DENIED
+    ) {
+      close();
+    } else {
+      console.log("resuming session");
+      setTimeout(connect, 1000);
+    }
+  }
+  function connectWebSocket(params: URLSearchParams) {
+    let welcomed = false;
+    const socket = new WebSocket(`http://localhost:3000/signal?${params}`);
+    sendSignal = (data) => socket.send(data);
+    closeSignal = () => socket.close();
+    socket.addEventListener("open", () => {
+      const hello: Handshake = { hello: { versions: [PROTOCOL_VERSION] } };
+      socket.send(JSON.stringify(hello));
+    });
+    socket.addEventListener("message", async (event) => {
+      if (!welcomed) {
+        welcomed = true;
+        await handleWelcome(JSON.parse(event.data) as Handshake);
+        return;
+      }
+      await handleMessage(JSON.parse(event.data) as ServerEnvelope);
+    });
+    socket.addEventListener("close", (event) => {
+      if (!welcomed && !started && event.code === CLOSE_ABNORMAL) {
+        console.log("websocket unavailable, falling back to server-sent events");
+        useSse = true;
+        connect();
+        return;
+      }
+      handleClose(event.code, event.reason);
+    });
+  }
+  function connectSse(params: URLSearchParams) {
+    params.set("versions", PROTOCOL_VERSION.toString());
+    const events = new EventSource(
+      `http://localhost:3000/signal/sse?${params}`
+    );
+    let endpoint = "";
+    let posting = Promise.resolve();
+    let closed = false;
+    function finish(code: number, reason: string) {
+      if (!closed) {
+        closed = true;
+        events.close();
+        handleClose(code, reason);
+      }
+    }
+    // Posts are chained so messages arrive in the order they were sent.
+    sendSignal = (data) => {
+      posting = posting
+        .then(async () => {
+          await fetch(`http://localhost:3000${endpoint}`, {
+            method: "POST",
+            headers: { "Content-Type": "application/json" },
+            body: data,
+          });
+        })
+        .catch((error) => console.error("signal post failed", error));
+    };
+    closeSignal = () => {
+      fetch(`http://localhost:3000${endpoint}`, { method: "DELETE" });
+      finish(1000, "");
+    };
+    events.addEventListener("endpoint", (event) => {
+      endpoint = event.data;
+    });
+    events.addEventListener("welcome", async (event) => {
+      await handleWelcome(JSON.parse(event.data) as Handshake);
+    });
+    events.addEventListener("message", async (event) => {
+      await handleMessage(JSON.parse(event.data) as ServerEnvelope);
+    });
+    events.addEventListener("close", (event) => {
+      const { code, reason } = JSON.parse(event.data);
+      finish(code, reason);
+    });
+    events.addEventListener("error", () => finish(CLOSE_ABNORMAL, ""));
+  }
+  function connect() {
+    const params = new URLSearchParams({ code });
+    if (resumeToken) {
+      params.set("resume", resumeToken);
+      params.set("last", lastMessageId.toString());
+    }
+    if (useSse) {
+      connectSse(params);
+    } else {
+      connectWebSocket(params);
+    }
+  }
+  connect();
 }
@@ -0,0 +1,12 @@
+// Generated by `cargo run --bin schema` from server/src/signal.rs, do not edit.
+
+export const PROTOCOL_VERSION = 1;
+export const CLOSE_UNSUPPORTED_VERSION = 4000;
+export const CLOSE_RESUME_FAILED = 4001;
+export const CLOSE_CONNECTION_TIMEOUT = 4002;
+export const CLOSE_JOIN_TIMEOUT = 4003;
+export const CLOSE_JOIN_REJECTED = 4004;
+export const CLOSE_KICKED = 4005;
+export const CLOSE_MEETING_ENDED = 4006;
+export const CLOSE_DENIED = 4007;
+export const FEATURE_END_OF_CANDIDATES = "endOfCandidates";
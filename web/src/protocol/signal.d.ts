@@ -0,0 +1,110 @@
+// Generated by `cargo run --bin schema` from server/src/signal.rs, do not edit.
+
+export type Handshake = { "hello": { versions: Array<number>, } } | { "welcome": number };
+
+export type ServerMessageId = { id: number, resumeToken: string, role: Role, permissions: Permissions, };
+
+export type Role = "host" | "coHost" | "participant" | "viewer";
+
+export type Permissions = { publishAudio: boolean, publishVideo: boolean, 
+/**
+ * Publishing a second video track, which is taken for a screen share.
+ */
+screenShare: boolean, chat: boolean, 
+/**
+ * Changing the roles of others, kicking and muting them, running the waiting room,
+ * breakout rooms and room settings, and ending the meeting.
+ */
+moderate: boolean, };
+
+export type ServerMessagePeer = { id: number, name: string, capabilities: Capabilities, role: Role, permissions: Permissions, };
+
+export type ServerMessageChat = { from: number, text: string, };
+
+export type MediaKind = "audio" | "video";
+
+export type ServerMessageMute = { peer: number, kind: MediaKind, by: number, };
+
+export type ServerMessageKnock = { id: number, name: string, 
+/**
+ * When it started waiting, in milliseconds since the Unix epoch.
+ */
+since: number, };
+
+export type ServerMessageBreakouts = { names: Array<string>, 
+/**
+ * Until they close and everyone returns to the main room.
+ */
+seconds: number, };
+
+export type RoomMetadata = { title?: string | null, description?: string | null, 
+/**
+ * Anything else clients want to keep with the room. The server doesn't look inside.
+ */
+attributes: Record<string, unknown>, };
+
+export type ServerMessageRoom = { 
+/**
+ * When the room was opened, in milliseconds since the Unix epoch.
+ */
+created: number, 
+/**
+ * Name of the peer that opened the room, absent for breakout rooms.
+ */
+creator: string | null, title?: string | null, description?: string | null, 
+/**
+ * Anything else clients want to keep with the room. The server doesn't look inside.
+ */
+attributes: Record<string, unknown>, };
+
+export type ErrorCode = "badMessage" | "badSdp" | "invalidCandidate" | "permissionDenied" | "roomFull" | "roomLocked" | "unknownRoom" | "notStarted" | "limitExceeded" | "internal";
+
+export type ServerMessageError = { id: number | null, code: ErrorCode, message: string, };
+
+export type ServerMessage = { "candidate": RTCIceCandidateInit } | { "endOfCandidates": string | null } | { "offer": string } | { "answer": string } | { "id": ServerMessageId } | { "resumed": number } | { "peers": Array<ServerMessagePeer> } | { "peerJoined": ServerMessagePeer } | { "peerUpdated": ServerMessagePeer } | { "peerLeft": number } | { "chat": ServerMessageChat } | { "kicked": number } | { "muted": ServerMessageMute } | { "unmuteRequested": ServerMessageMute } | { "unmuted": ServerMessageMute } | { "meetingEnded": number } | "waiting" | { "waitingRoom": boolean } | { "locked": boolean } | { "lobbyJoined": ServerMessageKnock } | { "lobbyLeft": number } | { "breakouts": ServerMessageBreakouts } | { "breakoutCountdown": number } | "breakoutsClosed" | { "moved": number | null } | { "room": ServerMessageRoom } | { "ack": number } | { "error": ServerMessageError };
+
+export type ServerEnvelope = { id: number, re?: number | null, message: ServerMessage, };
+
+export type Capabilities = { 
+/**
+ * Codec MIME types the client can decode, like `video/VP8`. Absent means all of them.
+ */
+codecs?: Array<string> | null, 
+/**
+ * How many video tracks the client can decode at once. Absent means no limit.
+ */
+maxVideoDecodes?: number | null, 
+/**
+ * Optional protocol features the client understands, see `FEATURE_*`.
+ */
+features: Array<string>, };
+
+export type Join = { name: string, token?: string, clientVersion: string, capabilities: Capabilities, 
+/**
+ * Required to join a room whose code was reserved with a passcode.
+ */
+passcode?: string, 
+/**
+ * Sets the metadata of a room that doesn't exist yet. Ignored otherwise.
+ */
+room?: RoomMetadata, };
+
+export type SetRole = { peer: number, role: Role, };
+
+export type Mute = { peer: number, kind: MediaKind, };
+
+export type OpenBreakouts = { names: Array<string>, 
+/**
+ * How long they stay open.
+ */
+seconds: number, };
+
+export type AssignBreakout = { peer: number, 
+/**
+ * Index of the breakout room, or none for the main room.
+ */
+room: number | null, };
+
+export type PeerMessage = { "join": Join } | { "candidate": RTCIceCandidateInit } | { "endOfCandidates": string | null } | "iceRestart" | { "offer": string } | { "answer": string } | { "pli": number } | { "setRole": SetRole } | { "chat": string } | { "kick": number } | { "mute": Mute } | { "askUnmute": Mute } | { "unmute": MediaKind } | "endMeeting" | { "setWaitingRoom": boolean } | { "setLocked": boolean } | { "admit": number } | { "deny": number } | { "openBreakouts": OpenBreakouts } | { "assignBreakout": AssignBreakout } | "shuffleBreakouts" | "closeBreakouts" | { "setRoom": RoomMetadata };
+
+export type PeerEnvelope = { id: number, re?: number | null, message: PeerMessage, };